@@ -0,0 +1,62 @@
+//! compare the boxed `Waiter` with the unboxed `SmallWaiter`, and the
+//! spinning wait with the parking one for a rsp from another coroutine
+//!
+//! run with `cargo bench --bench waiter`
+use may::go;
+use may_waiter::{SmallWaiter, Waiter};
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::Instant;
+
+const ROUNDS: usize = 1_000_000;
+const PING_ROUNDS: usize = 100_000;
+
+fn bench<F: FnMut()>(name: &str, rounds: usize, mut f: F) {
+    let start = Instant::now();
+    for _ in 0..rounds {
+        f();
+    }
+    let ns = start.elapsed().as_nanos() as f64 / rounds as f64;
+    println!("{name:<10} {ns:>8.1} ns/iter");
+}
+
+// the rsp is set by another coroutine right after the req arrives
+fn ping_pong(name: &str, spin_iters: usize) {
+    let req = Arc::new(Waiter::<usize>::new());
+    let rsp = Arc::new(Waiter::<usize>::new());
+
+    let (req_1, rsp_1) = (req.clone(), rsp.clone());
+    let h = go!(move || loop {
+        let v = req_1.wait_rsp(None).unwrap();
+        if v == usize::MAX {
+            break;
+        }
+        rsp_1.set_rsp(v);
+    });
+
+    bench(name, PING_ROUNDS, || {
+        req.set_rsp(black_box(42));
+        black_box(rsp.wait_rsp_spin(None, spin_iters).unwrap());
+    });
+
+    req.set_rsp(usize::MAX);
+    h.join().unwrap();
+}
+
+fn main() {
+    let waiter = Waiter::<usize>::new();
+    bench("boxed", ROUNDS, || {
+        waiter.set_rsp(black_box(42));
+        black_box(waiter.wait_rsp(None).unwrap());
+    });
+
+    let waiter = SmallWaiter::<usize>::new();
+    bench("unboxed", ROUNDS, || {
+        waiter.set_rsp(black_box(42));
+        black_box(waiter.wait_rsp(None).unwrap());
+    });
+
+    ping_pong("park", 0);
+    ping_pong("spin", 1000);
+}
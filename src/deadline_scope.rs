@@ -0,0 +1,70 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+// the ambient deadline of the current coroutine
+may::coroutine_local!(static DEADLINE: Cell<Option<Instant>> = Cell::new(None));
+
+/// set an ambient deadline for the waits in the current coroutine
+///
+/// the deadline is used by `Waiter::wait_rsp_scoped`, a nested scope could only
+/// shorten the deadline, the previous one is restored when the scope dropped
+#[derive(Debug)]
+pub struct DeadlineScope {
+    prev: Option<Instant>,
+    // the scope must be dropped on the coroutine that created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl DeadlineScope {
+    /// enter a scope that ends `timeout` later
+    pub fn new(timeout: Duration) -> Self {
+        DeadlineScope::until(Instant::now() + timeout)
+    }
+
+    /// enter a scope that ends at the deadline
+    pub fn until(deadline: Instant) -> Self {
+        let prev = DEADLINE.with(|d| {
+            let prev = d.get();
+            d.set(Some(prev.map_or(deadline, |p| p.min(deadline))));
+            prev
+        });
+        DeadlineScope {
+            prev,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// the ambient deadline of the current coroutine
+    pub fn current() -> Option<Instant> {
+        DEADLINE.with(|d| d.get())
+    }
+}
+
+impl Drop for DeadlineScope {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(self.prev));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_scope() {
+        assert_eq!(DeadlineScope::current(), None);
+        let outer = DeadlineScope::new(Duration::from_secs(10));
+        let deadline = DeadlineScope::current().unwrap();
+        {
+            let _inner = DeadlineScope::new(Duration::from_secs(1));
+            assert!(DeadlineScope::current().unwrap() < deadline);
+            // the longer one doesn't extend the deadline
+            let _longer = DeadlineScope::new(Duration::from_secs(100));
+            assert!(DeadlineScope::current().unwrap() < deadline);
+        }
+        assert_eq!(DeadlineScope::current(), Some(deadline));
+        drop(outer);
+        assert_eq!(DeadlineScope::current(), None);
+    }
+}
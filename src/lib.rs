@@ -1,9 +1,179 @@
+mod deadline_scope;
+mod notify;
+mod oneshot;
+mod small_waiter;
+mod stream_waiter;
+mod thread_waiter;
 mod token_waiter;
 mod waiter;
+mod waiter_group;
 mod waiter_map;
 mod waiter_slab;
 
-pub use token_waiter::{TokenWaiter, ID};
-pub use waiter::Waiter;
-pub use waiter_map::{MapWaiter, MapWaiterOwned, WaiterMap};
-pub use waiter_slab::{SlabWaiter, SlabWaiterOwned, WaiterSlab};
+pub use deadline_scope::DeadlineScope;
+pub use notify::Notify;
+pub use oneshot::{oneshot, WaiterReceiver, WaiterSender};
+pub use small_waiter::SmallWaiter;
+pub use stream_waiter::StreamWaiter;
+pub use thread_waiter::ThreadWaiter;
+pub use token_waiter::{IdError, TokenWaiter, ID};
+#[cfg(feature = "debug")]
+pub use waiter::{set_unconsumed_drop_hook, unconsumed_drops};
+pub use waiter::{CancelToken, WaitError, Waiter};
+pub use waiter_group::{GroupSetter, WaiterGroup};
+pub use waiter_map::{MapWaiter, MapWaiterOwned, MapWaiterRef, MapWaiterWeak, WaiterMap};
+pub use waiter_slab::{SlabId, SlabWaiter, SlabWaiterOwned, WaiterSlab};
+
+/// the `sharded_slab` config to tune the `WaiterSlab` by `WaiterSlab::with_config`
+pub use sharded_slab::Config as SlabConfig;
+
+use std::io;
+use std::time::Duration;
+
+/// the common interface of the containers that register waiters by id
+///
+/// `TokenWaiter` is not one of them, its id is derived from the waiter itself
+pub trait WaiterRegistry<T> {
+    /// the input to register a waiter, the key for `WaiterMap`, `()` for `WaiterSlab`
+    type Key;
+    /// the id to deliver the rsp
+    type Id;
+    /// the guard to wait the rsp, the waiter is removed when it's dropped
+    type Guard<'a>: WaiterGuard<T, Id = Self::Id>
+    where
+        Self: 'a;
+
+    /// register a new waiter
+    fn register(&self, key: Self::Key) -> Self::Guard<'_>;
+
+    /// set rsp for the waiter with the id
+    /// return the rsp back if there is no such waiter
+    fn set_rsp(&self, id: &Self::Id, rsp: T) -> Result<(), T>;
+}
+
+/// the guard returned by `WaiterRegistry::register`
+pub trait WaiterGuard<T> {
+    /// the id to deliver the rsp
+    type Id;
+
+    /// get the id of the waiter
+    fn id(&self) -> Self::Id;
+
+    /// wait for response
+    fn wait_rsp(&self, timeout: Option<Duration>) -> io::Result<T>;
+}
+
+/// a snapshot of the waiters in a `WaiterMap` or `WaiterSlab`
+///
+/// the waiters are scanned one by one, so the counts may not be consistent
+/// with each other under contention
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WaiterStats {
+    /// the number of waiters
+    pub total: usize,
+    /// the number of waiters that hold a rsp not yet taken
+    pub ready: usize,
+    /// the number of waiters that a coroutine is waiting on, see `Waiter::is_parked`
+    pub pending: usize,
+}
+
+impl WaiterStats {
+    // count the waiter into the stats
+    pub(crate) fn add<T, M>(&mut self, waiter: &Waiter<T, M>) {
+        self.total += 1;
+        if waiter.is_ready() {
+            self.ready += 1;
+        }
+        if waiter.is_parked() {
+            self.pending += 1;
+        }
+    }
+}
+
+impl<K, T, S, M> WaiterRegistry<T> for WaiterMap<K, T, S, M>
+where
+    K: std::hash::Hash + Eq + Clone,
+    S: std::hash::BuildHasher,
+    M: Default,
+{
+    type Key = K;
+    type Id = K;
+    type Guard<'a>
+        = MapWaiter<'a, K, T, S, M>
+    where
+        Self: 'a;
+
+    fn register(&self, key: K) -> MapWaiter<'_, K, T, S, M> {
+        self.new_waiter(key)
+    }
+
+    fn set_rsp(&self, id: &K, rsp: T) -> Result<(), T> {
+        WaiterMap::set_rsp(self, id, rsp).map(drop)
+    }
+}
+
+impl<K, T, S, M> WaiterGuard<T> for MapWaiter<'_, K, T, S, M>
+where
+    K: std::hash::Hash + Eq + Clone,
+    S: std::hash::BuildHasher,
+{
+    type Id = K;
+
+    fn id(&self) -> K {
+        MapWaiter::id(self).clone()
+    }
+
+    fn wait_rsp(&self, timeout: Option<Duration>) -> io::Result<T> {
+        MapWaiter::wait_rsp(self, timeout)
+    }
+}
+
+impl<T, M: Default, C: sharded_slab::Config> WaiterRegistry<T> for WaiterSlab<T, M, C> {
+    type Key = ();
+    type Id = SlabId<T>;
+    type Guard<'a>
+        = SlabWaiter<'a, T, M, C>
+    where
+        Self: 'a;
+
+    fn register(&self, _key: ()) -> SlabWaiter<'_, T, M, C> {
+        self.new_waiter()
+    }
+
+    fn set_rsp(&self, id: &SlabId<T>, rsp: T) -> Result<(), T> {
+        WaiterSlab::set_rsp(self, *id, rsp)
+    }
+}
+
+impl<T, M, C: sharded_slab::Config> WaiterGuard<T> for SlabWaiter<'_, T, M, C> {
+    type Id = SlabId<T>;
+
+    fn id(&self) -> SlabId<T> {
+        SlabWaiter::id(self)
+    }
+
+    fn wait_rsp(&self, timeout: Option<Duration>) -> io::Result<T> {
+        SlabWaiter::wait_rsp(self, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry_roundtrip<R: WaiterRegistry<usize>>(registry: &R, key: R::Key) {
+        let waiter = registry.register(key);
+        let id = waiter.id();
+        registry.set_rsp(&id, 100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        drop(waiter);
+        // the waiter is removed with the guard
+        assert_eq!(registry.set_rsp(&id, 200), Err(200));
+    }
+
+    #[test]
+    fn waiter_registry() {
+        registry_roundtrip(&WaiterMap::<usize, usize>::new(), 1234);
+        registry_roundtrip(&WaiterSlab::<usize>::new(), ());
+    }
+}
@@ -0,0 +1,98 @@
+use may::coroutine::{self, ParkError};
+use may::sync::Blocker;
+
+use crate::waiter::WaitError;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+/// Waiter for a notification without payload
+///
+/// it only flips a flag and unparks, so unlike `Waiter<()>` it has no rsp slot,
+/// set lock, observers or cancel state, and the struct is much smaller
+pub struct Notify {
+    blocker: Blocker,
+    notified: AtomicBool,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Notify {
+            // the wait decides how to deal with the coroutine cancel
+            blocker: Blocker::new(true),
+            notified: AtomicBool::new(false),
+        }
+    }
+
+    /// wake up the waiter, the notifications before the wait are merged into one
+    pub fn notify(&self) {
+        self.notified.store(true, Ordering::Release);
+        self.blocker.unpark();
+    }
+
+    /// wait for the notification
+    pub fn wait<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<()> {
+        let deadline = timeout.into().map(|d| Instant::now() + d);
+        loop {
+            if self.notified.swap(false, Ordering::AcqRel) {
+                return Ok(());
+            }
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            // the unpark left by a consumed notification would just loop again
+            match self.blocker.park(timeout) {
+                Ok(_) => {}
+                Err(ParkError::Timeout) => return Err(WaitError::Timeout.into()),
+                // the coroutine cancel unwinds like `Waiter::wait_rsp`
+                Err(ParkError::Canceled) => coroutine::trigger_cancel_panic(),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Notify{{ ... }}")
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+    use std::sync::Arc;
+
+    #[test]
+    fn notify() {
+        let notify = Arc::new(Notify::new());
+        let notify_1 = notify.clone();
+
+        // notify in another coroutine
+        go!(move || notify_1.notify());
+        notify.wait(None).unwrap();
+
+        let err = notify.wait(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // the notifications are merged
+        notify.notify();
+        notify.notify();
+        notify.wait(None).unwrap();
+        assert!(notify.wait(Duration::from_millis(10)).is_err());
+    }
+
+    #[test]
+    fn notify_canceled() {
+        let h = go!(|| Notify::new().wait(None));
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound by the cancel panic
+        assert!(h.join().is_err());
+    }
+}
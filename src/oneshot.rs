@@ -0,0 +1,148 @@
+use crate::waiter::{WaitError, Waiter};
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+struct Inner<T> {
+    waiter: Waiter<T>,
+    // set when the sender is dropped without setting the rsp
+    disconnected: AtomicBool,
+}
+
+/// the sending half of `oneshot`, the rsp could only be set once
+pub struct WaiterSender<T> {
+    inner: Arc<Inner<T>>,
+    sent: bool,
+}
+
+/// the receiving half of `oneshot`, it could only wait for the rsp
+pub struct WaiterReceiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// create a one shot pair of the sender and the receiver
+///
+/// the wait of the receiver returns `WaitError::Disconnected` if the sender
+/// is dropped without setting the rsp
+pub fn oneshot<T>() -> (WaiterSender<T>, WaiterReceiver<T>) {
+    let inner = Arc::new(Inner {
+        waiter: Waiter::new(),
+        disconnected: AtomicBool::new(false),
+    });
+    let sender = WaiterSender {
+        inner: inner.clone(),
+        sent: false,
+    };
+    (sender, WaiterReceiver { inner })
+}
+
+impl<T> WaiterSender<T> {
+    /// set the rsp and wake up the receiver
+    pub fn set_rsp(mut self, rsp: T) {
+        self.sent = true;
+        self.inner.waiter.set_rsp(rsp);
+    }
+}
+
+impl<T> Drop for WaiterSender<T> {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.inner.disconnected.store(true, Ordering::Release);
+            // wake up the receiver without rsp
+            self.inner.waiter.cancel_wait();
+        }
+    }
+}
+
+impl<T> WaiterReceiver<T> {
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
+        self.wait(timeout.into(), true).map_err(io::Error::from)
+    }
+
+    /// wait for the rsp and report why it failed with `WaitError`
+    ///
+    /// a canceled coroutine gets `Canceled` instead of the cancel panic
+    pub fn wait_rsp_result<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<T, WaitError> {
+        self.wait(timeout.into(), false)
+    }
+
+    fn wait(&self, timeout: Option<Duration>, cancel_panic: bool) -> Result<T, WaitError> {
+        // the sender is gone, no rsp would come any more
+        if self.inner.disconnected.load(Ordering::Acquire) {
+            return Err(WaitError::Disconnected);
+        }
+        let ret = self.inner.waiter.wait_rsp_boxed(timeout, cancel_panic);
+        match ret.map(|rsp| *rsp) {
+            Err(WaitError::Canceled) if self.inner.disconnected.load(Ordering::Acquire) => {
+                Err(WaitError::Disconnected)
+            }
+            ret => ret,
+        }
+    }
+
+    /// try to get the rsp without blocking
+    pub fn try_wait_rsp(&self) -> Option<T> {
+        self.inner.waiter.try_wait_rsp()
+    }
+}
+
+impl<T> std::fmt::Debug for WaiterSender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WaiterSender{{ ... }}")
+    }
+}
+
+impl<T> std::fmt::Debug for WaiterReceiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WaiterReceiver{{ ... }}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+
+    #[test]
+    fn oneshot_set_rsp() {
+        let (tx, rx) = oneshot::<usize>();
+        go!(move || tx.set_rsp(42));
+        assert_eq!(rx.wait_rsp(None).unwrap(), 42);
+    }
+
+    #[test]
+    fn oneshot_sender_dropped() {
+        let (tx, rx) = oneshot::<usize>();
+        let err = rx.wait_rsp_result(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err, WaitError::Timeout);
+
+        go!(move || drop(tx));
+        assert_eq!(rx.wait_rsp_result(None), Err(WaitError::Disconnected));
+        // the later wait would not block
+        let err = rx.wait_rsp(None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn oneshot_canceled() {
+        let h = go!(|| {
+            let (_tx, rx) = oneshot::<usize>();
+            rx.wait_rsp(None)
+        });
+        // make sure the coroutine is parked
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound by the cancel panic
+        assert!(h.join().is_err());
+
+        let h = go!(|| {
+            let (_tx, rx) = oneshot::<usize>();
+            rx.wait_rsp_result(None)
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        assert_eq!(h.join().unwrap(), Err(WaitError::Canceled));
+    }
+}
@@ -0,0 +1,138 @@
+use crossbeam_utils::atomic::AtomicCell;
+use may::coroutine::{self, ParkError};
+use may::sync::Blocker;
+
+use crate::waiter::WaitError;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+/// Waiter that store the response inline without boxing it
+///
+/// the rsp is kept in an `AtomicCell`, which is lock free when `Option<T>` fits
+/// in a native atomic and falls back to a global seq lock otherwise, in both
+/// cases there is no heap allocation for each response
+pub struct SmallWaiter<T> {
+    blocker: Blocker,
+    rsp: AtomicCell<Option<T>>,
+    // set by cancel_wait
+    canceled: AtomicBool,
+}
+
+impl<T> SmallWaiter<T> {
+    pub fn new() -> Self {
+        SmallWaiter {
+            // the wait decides how to deal with the coroutine cancel
+            blocker: Blocker::new(true),
+            rsp: AtomicCell::new(None),
+            canceled: AtomicBool::new(false),
+        }
+    }
+
+    /// set the rsp and wake up the waiter
+    /// return the previous rsp if it was not consumed yet
+    pub fn set_rsp(&self, rsp: T) -> Option<T> {
+        let old = self.rsp.swap(Some(rsp));
+        // wake up the blocker
+        self.blocker.unpark();
+        old
+    }
+
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
+        self.wait(timeout.into(), true).map_err(io::Error::from)
+    }
+
+    /// wait for the rsp and report why it failed with `WaitError`
+    ///
+    /// a canceled coroutine gets `Canceled` instead of the cancel panic
+    pub fn wait_rsp_result<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<T, WaitError> {
+        self.wait(timeout.into(), false)
+    }
+
+    fn wait(&self, timeout: Option<Duration>, cancel_panic: bool) -> Result<T, WaitError> {
+        let deadline = timeout.map(|d| Instant::now() + d);
+        loop {
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            match self.blocker.park(timeout) {
+                Ok(_) => {
+                    if let Some(rsp) = self.rsp.swap(None) {
+                        return Ok(rsp);
+                    }
+                    // waked up by cancel_wait without rsp
+                    if self.canceled.swap(false, Ordering::AcqRel) {
+                        return Err(WaitError::Canceled);
+                    }
+                    // the wake up left by a rsp that is already taken, wait again
+                }
+                Err(ParkError::Timeout) => return Err(WaitError::Timeout),
+                Err(ParkError::Canceled) if cancel_panic => coroutine::trigger_cancel_panic(),
+                Err(ParkError::Canceled) => return Err(WaitError::Canceled),
+            }
+        }
+    }
+
+    pub fn cancel_wait(&self) {
+        self.canceled.store(true, Ordering::Release);
+        // wake up the blocker without rsp
+        self.blocker.unpark()
+    }
+}
+
+impl<T> fmt::Debug for SmallWaiter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SmallWaiter{{ ... }}")
+    }
+}
+
+impl<T> Default for SmallWaiter<T> {
+    fn default() -> Self {
+        SmallWaiter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+    use std::sync::Arc;
+
+    #[test]
+    fn small_waiter() {
+        let waiter = Arc::new(SmallWaiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+
+        // trigger the rsp in another coroutine
+        go!(move || waiter_1.set_rsp(100));
+
+        // this will block until the rsp was set
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+
+        waiter.cancel_wait();
+        let err = waiter.wait_rsp(None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn small_waiter_stale_wakeup() {
+        let waiter = SmallWaiter::<usize>::new();
+        // a wake up without rsp, e.g. left by a rsp that is already taken
+        waiter.blocker.unpark();
+        let ret = waiter.wait_rsp_result(Duration::from_millis(20));
+        assert_eq!(ret, Err(WaitError::Timeout));
+    }
+
+    #[test]
+    fn small_waiter_canceled() {
+        let h = go!(|| SmallWaiter::<usize>::new().wait_rsp(None));
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound by the cancel panic
+        assert!(h.join().is_err());
+
+        let h = go!(|| SmallWaiter::<usize>::new().wait_rsp_result(None));
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        assert_eq!(h.join().unwrap(), Err(WaitError::Canceled));
+    }
+}
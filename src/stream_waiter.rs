@@ -0,0 +1,203 @@
+use may::coroutine::{self, ParkError};
+use may::sync::Blocker;
+
+use crate::waiter::WaitError;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Waiter that could receive a sequence of responses
+///
+/// the pushed values are buffered and returned by `wait_next` in order,
+/// after `close` the left values could still be drained
+pub struct StreamWaiter<T> {
+    blocker: Blocker,
+    queue: Mutex<VecDeque<T>>,
+    closed: AtomicBool,
+}
+
+impl<T> StreamWaiter<T> {
+    pub fn new() -> Self {
+        StreamWaiter {
+            // the wait decides how to deal with the coroutine cancel
+            blocker: Blocker::new(true),
+            queue: Mutex::new(VecDeque::new()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// push a value and wake up the waiter
+    /// return the value back if the stream is already closed
+    pub fn push(&self, value: T) -> Result<(), T> {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            // check under the lock so that no value is pushed after close
+            if self.closed.load(Ordering::Acquire) {
+                return Err(value);
+            }
+            queue.push_back(value);
+        }
+        self.blocker.unpark();
+        Ok(())
+    }
+
+    /// close the stream, the waiter would get `None` after all values drained
+    pub fn close(&self) {
+        {
+            let _queue = self.queue.lock().unwrap();
+            self.closed.store(true, Ordering::Release);
+        }
+        self.blocker.unpark();
+    }
+
+    /// check if the stream is closed
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// wait for the next value, return `None` if the stream is closed and drained
+    pub fn wait_next<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<Option<T>> {
+        let deadline = timeout.into().map(|d| Instant::now() + d);
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(value) = queue.pop_front() {
+                    return Ok(Some(value));
+                }
+                if self.closed.load(Ordering::Acquire) {
+                    return Ok(None);
+                }
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        return Err(WaitError::Timeout.into());
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+
+            // a push after the check would leave the unpark, so we would not miss it
+            match self.blocker.park(timeout) {
+                Ok(_) => {}
+                Err(ParkError::Timeout) => return Err(WaitError::Timeout.into()),
+                // the coroutine cancel unwinds like `Waiter::wait_rsp`
+                Err(ParkError::Canceled) => coroutine::trigger_cancel_panic(),
+            }
+        }
+    }
+
+    /// wait until at least one value arrives, then take all the buffered values
+    ///
+    /// return an empty vec if the wait times out, or the stream is closed and drained
+    pub fn drain_available(&self, timeout: Duration) -> Vec<T> {
+        let first = match self.wait_next(timeout) {
+            Ok(Some(value)) => value,
+            _ => return Vec::new(),
+        };
+        let mut queue = self.queue.lock().unwrap();
+        let mut values = Vec::with_capacity(queue.len() + 1);
+        values.push(first);
+        values.extend(queue.drain(..));
+        values
+    }
+}
+
+impl<T> fmt::Debug for StreamWaiter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StreamWaiter{{ closed: {} }}", self.is_closed())
+    }
+}
+
+impl<T> Default for StreamWaiter<T> {
+    fn default() -> Self {
+        StreamWaiter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+    use std::sync::Arc;
+
+    #[test]
+    fn stream_waiter() {
+        let waiter = Arc::new(StreamWaiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+
+        go!(move || {
+            for i in 0..10 {
+                waiter_1.push(i).unwrap();
+            }
+        });
+
+        for i in 0..10 {
+            assert_eq!(waiter.wait_next(None).unwrap(), Some(i));
+        }
+        let err = waiter.wait_next(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn stream_waiter_close() {
+        let waiter = StreamWaiter::<usize>::new();
+        waiter.push(1).unwrap();
+        waiter.push(2).unwrap();
+        waiter.close();
+        assert_eq!(waiter.push(3), Err(3));
+
+        // the left values are still drained after close
+        assert_eq!(waiter.wait_next(None).unwrap(), Some(1));
+        assert_eq!(waiter.wait_next(None).unwrap(), Some(2));
+        assert_eq!(waiter.wait_next(None).unwrap(), None);
+        assert_eq!(waiter.wait_next(None).unwrap(), None);
+    }
+
+    #[test]
+    fn drain_available() {
+        let waiter = Arc::new(StreamWaiter::<usize>::new());
+        let timeout = Duration::from_millis(10);
+        assert!(waiter.drain_available(timeout).is_empty());
+
+        // the buffered values are coalesced into one drain
+        for i in 0..5 {
+            waiter.push(i).unwrap();
+        }
+        assert_eq!(waiter.drain_available(timeout), vec![0, 1, 2, 3, 4]);
+
+        let waiter_1 = waiter.clone();
+        go!(move || {
+            may::coroutine::sleep(Duration::from_millis(10));
+            for i in 5..10 {
+                waiter_1.push(i).unwrap();
+            }
+            waiter_1.close();
+        });
+        let mut values = vec![];
+        loop {
+            let drained = waiter.drain_available(Duration::from_secs(2));
+            if drained.is_empty() {
+                break;
+            }
+            values.extend(drained);
+        }
+        assert_eq!(values, (5..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn stream_waiter_canceled() {
+        let h = go!(|| StreamWaiter::<usize>::new().wait_next(None));
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound by the cancel panic
+        assert!(h.join().is_err());
+    }
+}
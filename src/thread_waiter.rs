@@ -0,0 +1,114 @@
+use crate::waiter::WaitError;
+
+use std::fmt;
+use std::io;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+struct State<T> {
+    rsp: Option<T>,
+    // waked up by cancel_wait without rsp
+    canceled: bool,
+}
+
+/// Waiter that blocks the OS thread instead of the coroutine
+///
+/// `Waiter` parks the running coroutine and should be used inside coroutines,
+/// use `ThreadWaiter` when the rsp is waited from plain threads, it's built on
+/// `Mutex` and `Condvar` so it would block the whole thread while waiting
+pub struct ThreadWaiter<T> {
+    state: Mutex<State<T>>,
+    cond: Condvar,
+}
+
+impl<T> ThreadWaiter<T> {
+    pub fn new() -> Self {
+        ThreadWaiter {
+            state: Mutex::new(State {
+                rsp: None,
+                canceled: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// set the rsp and wake up the waiter
+    /// return the previous rsp if it was not consumed yet
+    pub fn set_rsp(&self, rsp: T) -> Option<T> {
+        let old = self.state.lock().unwrap().rsp.replace(rsp);
+        self.cond.notify_one();
+        old
+    }
+
+    /// wait for the rsp, blocking the current thread
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
+        let deadline = timeout.into().map(|d| Instant::now() + d);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(rsp) = state.rsp.take() {
+                return Ok(rsp);
+            }
+            if state.canceled {
+                state.canceled = false;
+                return Err(WaitError::Canceled.into());
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        return Err(WaitError::Timeout.into());
+                    }
+                    self.cond.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.cond.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// try to get the rsp without blocking
+    pub fn try_wait_rsp(&self) -> Option<T> {
+        self.state.lock().unwrap().rsp.take()
+    }
+
+    /// wake up the waiter without rsp
+    pub fn cancel_wait(&self) {
+        self.state.lock().unwrap().canceled = true;
+        self.cond.notify_one();
+    }
+}
+
+impl<T> fmt::Debug for ThreadWaiter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ThreadWaiter{{ ... }}")
+    }
+}
+
+impl<T> Default for ThreadWaiter<T> {
+    fn default() -> Self {
+        ThreadWaiter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn thread_waiter() {
+        let waiter = Arc::new(ThreadWaiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+
+        let h = std::thread::spawn(move || waiter_1.wait_rsp(None));
+        std::thread::sleep(Duration::from_millis(10));
+        waiter.set_rsp(42);
+        assert_eq!(h.join().unwrap().unwrap(), 42);
+
+        let err = waiter.wait_rsp(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        waiter.cancel_wait();
+        let err = waiter.wait_rsp(None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    }
+}
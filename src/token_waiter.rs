@@ -1,14 +1,54 @@
-use crate::waiter::Waiter;
+use crate::waiter::{WaitError, Waiter};
 
-use std::cell::Cell;
 use std::fmt;
 use std::io;
 use std::marker::PhantomPinned;
 use std::num::NonZeroUsize;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-thread_local! {static TAG: Cell<usize> = const { Cell::new(0) }}
+// the id layout is `| address >> ADDR_SHIFT | tag | tag width | lock |`
+// the lowest bit is used as a lock when delivering the rsp
+const LOCK_BITS: u32 = 1;
+// the tag width is kept in the id, so that the address could be decoded
+// before knowing which waiter it points to
+#[cfg(target_pointer_width = "64")]
+const WIDTH_BITS: u32 = 4;
+#[cfg(not(target_pointer_width = "64"))]
+const WIDTH_BITS: u32 = 0;
+const WIDTH_MASK: usize = (1 << WIDTH_BITS) - 1;
+const TAG_SHIFT: u32 = LOCK_BITS + WIDTH_BITS;
+// the waiter address is aligned, so its low bits are dropped
+const ADDR_SHIFT: u32 = std::mem::align_of::<usize>().trailing_zeros();
+// the valid bits of a user space address
+#[cfg(target_pointer_width = "64")]
+const ADDR_BITS: u32 = 48;
+#[cfg(not(target_pointer_width = "64"))]
+const ADDR_BITS: u32 = usize::BITS;
+// the max tag bits that still keep a full user space address in the id
+const MAX_TAG_BITS: u32 = {
+    let bits = usize::BITS - TAG_SHIFT - (ADDR_BITS - ADDR_SHIFT);
+    if bits < WIDTH_MASK as u32 {
+        bits
+    } else {
+        WIDTH_MASK as u32
+    }
+};
+
+// each issued id draw a new generation as the tag, so that a waiter that
+// reuses the address of a dropped one would not accept the stale id
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+// decode the waiter address from the id, reject the malformed tag width and
+// the null address
+fn decode_address(id: usize) -> Option<usize> {
+    let width = ((id >> LOCK_BITS) & WIDTH_MASK) as u32;
+    if width > MAX_TAG_BITS {
+        return None;
+    }
+    let address = (id >> (TAG_SHIFT + width)) << ADDR_SHIFT;
+    (address != 0).then_some(address)
+}
 
 /// the id type from `TokenWaiter::get_id()`
 #[derive(Debug)]
@@ -23,6 +63,55 @@ impl ID {
     pub unsafe fn from_usize(id: usize) -> Self {
         ID(NonZeroUsize::new(id).expect("id should not be zero"))
     }
+
+    /// parse the id from the hex string of its `Display`
+    ///
+    /// # Safety
+    ///
+    /// the same as `ID::from_usize`, the string must come from the `Display` of
+    /// an `ID` in the same process, other values could lead to invalid memory access
+    pub unsafe fn parse(s: &str) -> Result<Self, IdError> {
+        usize::from_str_radix(s, 16)
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .map(ID)
+            .ok_or(IdError::Invalid)
+    }
+
+    /// construct `ID` from `usize`, return `None` if it's not a well formed id
+    ///
+    /// # Safety
+    ///
+    /// only the layout of the id is checked, the usize must still come from an
+    /// `ID` issued by a `TokenWaiter` that is alive in the same process
+    pub unsafe fn from_usize_checked(id: usize) -> Option<Self> {
+        // the lock bit is never set in an issued id
+        if id & 1 == 1 {
+            return None;
+        }
+        decode_address(id)?;
+        NonZeroUsize::new(id).map(ID)
+    }
+
+    /// duplicate the id to send the request again
+    ///
+    /// the id carries the generation of the waiter, so only the first of the
+    /// copies could deliver the rsp, the others are rejected after that
+    ///
+    /// # Safety
+    ///
+    /// the id points to the waiter, every copy must be used or dropped before
+    /// the `TokenWaiter` is dropped
+    pub unsafe fn clone_for_retry(&self) -> ID {
+        ID(self.0)
+    }
+}
+
+/// format the id as a hex string
+impl fmt::Display for ID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}", self.0)
+    }
 }
 
 impl From<ID> for usize {
@@ -35,10 +124,37 @@ impl From<ID> for usize {
 #[derive(Debug)]
 pub struct Error;
 
+/// the reason why `TokenWaiter::try_id()` failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum IdError {
+    /// the previous id is not triggered yet
+    AlreadyIssued,
+    /// the id is malformed
+    Invalid,
+    /// the waiter address doesn't fit in the id along with the tag
+    AddressOverflow,
+}
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdError::AlreadyIssued => write!(f, "id already issued"),
+            IdError::Invalid => write!(f, "invalid id"),
+            IdError::AddressOverflow => write!(f, "waiter address doesn't fit in the id"),
+        }
+    }
+}
+
+impl std::error::Error for IdError {}
+
 /// token waiter that could be used for primitive wait blocking
 pub struct TokenWaiter<T> {
     waiter: Waiter<T>,
     key: AtomicUsize,
+    tag_bits: u32,
+    // keep the id valid after the rsp delivered
+    stable: bool,
     _phantom: PhantomPinned,
 }
 
@@ -47,28 +163,68 @@ impl<T> TokenWaiter<T> {
         TokenWaiter {
             key: AtomicUsize::new(0),
             waiter: Waiter::new(),
+            tag_bits: MAX_TAG_BITS,
+            stable: false,
             _phantom: PhantomPinned,
         }
     }
 
+    /// create a token waiter whose id keeps valid across the rsps
+    ///
+    /// `set_rsp` doesn't release the id, so the same id could deliver the rsp
+    /// of each round without issuing a new one, until `release` is called.
+    /// the id is never invalidated by a timeout, so a late rsp of the previous
+    /// round would be taken as the rsp of the current round
+    pub fn new_stable_id() -> Self {
+        TokenWaiter {
+            stable: true,
+            ..TokenWaiter::new()
+        }
+    }
+
+    /// create a token waiter that use `bits` of the id as the tag
+    ///
+    /// the tag distinguishes the ids issued at the same address, and the rest
+    /// of the id keeps the waiter address. more tag bits make a stale id less
+    /// likely to match, while fewer tag bits leave more room for the address.
+    /// the default uses all the bits left by a 48 bits user space address
+    ///
+    /// panic if `bits` is larger than the default
+    pub fn with_tag_bits(bits: u8) -> Self {
+        let bits = bits as u32;
+        assert!(bits <= MAX_TAG_BITS, "at most {MAX_TAG_BITS} tag bits");
+        TokenWaiter {
+            tag_bits: bits,
+            ..TokenWaiter::new()
+        }
+    }
+
     /// get the id of this token_waiter
     /// if the waiter is not triggered, we can't get id again
     pub fn id(&self) -> Result<ID, Error> {
+        self.try_id().map_err(|_| Error)
+    }
+
+    /// get the id of this token_waiter, report why it failed with `IdError`
+    pub fn try_id(&self) -> Result<ID, IdError> {
         let id = self.key.load(Ordering::Relaxed);
         if id != 0 {
             // the id is already initialized
-            return Err(Error);
+            return Err(IdError::AlreadyIssued);
         }
 
         // pin address is never changed
         let address = self as *const _ as usize;
-        let tag = TAG.with(|t| {
-            let x = t.get();
-            t.set(x + 1);
-            (x & 0x1f) << 1
-        });
+        let address = address >> ADDR_SHIFT;
+        let addr_shift = TAG_SHIFT + self.tag_bits;
+        // make sure the address is not truncated
+        if (address << addr_shift) >> addr_shift != address {
+            return Err(IdError::AddressOverflow);
+        }
+        let tag = GENERATION.fetch_add(1, Ordering::Relaxed) & ((1 << self.tag_bits) - 1);
 
-        let id = (address << 3) | tag;
+        let width = (self.tag_bits as usize) << LOCK_BITS;
+        let id = (address << addr_shift) | (tag << TAG_SHIFT) | width;
         self.key.store(id, Ordering::Relaxed);
         Ok(ID(NonZeroUsize::new(id).unwrap()))
     }
@@ -76,11 +232,18 @@ impl<T> TokenWaiter<T> {
     // make sure the id valid one from get id
     fn from_id(id: &ID) -> Option<&Self> {
         let id = id.0.get();
+        // the lock bit is never set in an issued id, such an id would match
+        // the key of a waiter that is being locked
+        if id & 1 == 1 {
+            return None;
+        }
         // TODO: how to check if the address is valid?
         // if the id is wrong enough we could get a SIGSEGV
-        let address = (id >> 3) & !0x7;
+        let address = decode_address(id)?;
         let waiter = unsafe { &*(address as *const Self) };
         // need to check if the memory is still valid
+        // the key contains the generation, a waiter at a reused address would
+        // hold a different one and reject the id
         // lock the key to protect contention with drop
         if waiter
             .key
@@ -94,18 +257,201 @@ impl<T> TokenWaiter<T> {
     }
 
     pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
-        self.waiter.wait_rsp(timeout)
+        let ret = match self
+            .waiter
+            .wait_rsp_boxed(timeout.into(), true)
+            .map(|rsp| *rsp)
+        {
+            Err(WaitError::Timeout) => self.wait_failed().ok_or(WaitError::Timeout),
+            ret => ret,
+        };
+        self.wait_unlock();
+        ret.map_err(io::Error::from)
+    }
+
+    /// wait for the rsp until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> io::Result<T> {
+        let now = Instant::now();
+        if deadline <= now {
+            let ret = self.wait_failed().ok_or(WaitError::Timeout);
+            self.wait_unlock();
+            return ret.map_err(io::Error::from);
+        }
+        self.wait_rsp(deadline - now)
+    }
+
+    /// wait for the rsp, if failed return a new valid id along with the error
+    /// so that the caller could register the waiter again for a retry
+    pub fn wait_rsp_or_id<D: Into<Option<Duration>>>(
+        &self,
+        timeout: D,
+    ) -> Result<T, (WaitError, ID)> {
+        let ret = match self
+            .waiter
+            .wait_rsp_boxed(timeout.into(), true)
+            .map(|rsp| *rsp)
+        {
+            Ok(rsp) => Ok(rsp),
+            Err(e) => match self.wait_failed() {
+                Some(rsp) => Ok(rsp),
+                // the key is released, so we can issue a new id
+                None => match self.key.load(Ordering::Acquire) {
+                    0 => Err((e, self.try_id().expect("the key was already released"))),
+                    // the stable id is still valid
+                    id => Err((e, ID(NonZeroUsize::new(id).unwrap()))),
+                },
+            },
+        };
+        self.wait_unlock();
+        ret
+    }
+
+    /// wait for the rsp for at most `attempts` times, each for `per_attempt`
+    ///
+    /// after a timeout `on_retry` is called with a new valid id to send the
+    /// request again, the last error is returned if all attempts failed
+    pub fn wait_rsp_retry<D, F>(
+        &self,
+        per_attempt: D,
+        attempts: usize,
+        mut on_retry: F,
+    ) -> io::Result<T>
+    where
+        D: Into<Option<Duration>>,
+        F: FnMut(ID),
+    {
+        let per_attempt = per_attempt.into();
+        let mut left = attempts.max(1);
+        loop {
+            left -= 1;
+            match self.wait_rsp_or_id(per_attempt) {
+                Ok(rsp) => return Ok(rsp),
+                Err((WaitError::Timeout, id)) if left > 0 => on_retry(id),
+                Err((e, _id)) => {
+                    // release the id that is not used for a retry
+                    let ret = self.wait_failed().ok_or(e);
+                    self.wait_unlock();
+                    return ret.map_err(io::Error::from);
+                }
+            }
+        }
+    }
+
+    /// take the rsp that arrived after the wait returned
+    ///
+    /// a failed wait releases the id, so a late rsp could only arrive through
+    /// an id issued again, e.g. the one returned by `wait_rsp_or_id`
+    pub fn take_rsp(&self) -> Option<T> {
+        let rsp = self.waiter.take_rsp()?;
+        // the sender may be releasing the key
+        self.wait_unlock();
+        Some(rsp)
+    }
+
+    /// release the issued id, the `set_rsp` with it would not deliver any more
+    ///
+    /// this abandons the id without waiting, e.g. when the request failed to send,
+    /// so that a new id could be issued by `id()`.
+    /// the rsp that is already delivered is kept and could be got by `take_rsp`
+    pub fn release(&self) {
+        loop {
+            let id = self.key.load(Ordering::Acquire);
+            if id == 0 {
+                return;
+            }
+            if id & 1 == 1 {
+                // the sender is delivering the rsp
+                std::hint::spin_loop();
+                continue;
+            }
+            if self
+                .key
+                .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    // the wait failed, return the rsp if it's delivered at the same time
+    fn wait_failed(&self) -> Option<T> {
+        if self.stable {
+            // the stable id is kept, only pick up the rsp racing with the failure
+            self.wait_unlock();
+            return self.waiter.take_rsp();
+        }
+        self.reclaim_id()
+    }
+
+    // reset the key so that the id could be issued again
+    // if the id is already claimed by a sender, the rsp is on the way and returned
+    fn reclaim_id(&self) -> Option<T> {
+        loop {
+            let id = self.key.load(Ordering::Acquire);
+            if id == 0 {
+                // no id issued, or the sender already delivered the rsp
+                if !self.waiter.is_ready() {
+                    return None;
+                }
+                // consume the wake up together with the rsp
+                return self.waiter.wait_rsp_result(None).ok();
+            }
+            if id & 1 == 1 {
+                // the sender is delivering the rsp
+                std::hint::spin_loop();
+                continue;
+            }
+            if self
+                .key
+                .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                // the late set_rsp would not match the id any more
+                return None;
+            }
+        }
+    }
+
+    // the sender release the key after the rsp delivered, wait for it so that
+    // the id could be issued again right after the wait returns
+    fn wait_unlock(&self) {
+        while self.key.load(Ordering::Acquire) & 1 == 1 {
+            std::hint::spin_loop();
+        }
     }
 
     /// set rsp for the waiter with id
     /// the `id` must be come from `get_id()`
-    pub fn set_rsp(id: ID, rsp: T) {
-        if let Some(waiter) = Self::from_id(&id) {
-            // clear the id so that we can get the id again
-            waiter.key.store(0, Ordering::Release);
-            // wake up the blocker
-            waiter.waiter.set_rsp(rsp);
+    /// return the rsp back if the id is no longer valid
+    pub fn set_rsp(id: ID, rsp: T) -> Result<(), T> {
+        match Self::from_id(&id) {
+            Some(waiter) => {
+                // wake up the blocker
+                waiter.waiter.set_rsp(rsp);
+                // clear the id so that we can get the id again
+                // the waiter would not leave until the key is released
+                let key = if waiter.stable { id.0.get() } else { 0 };
+                waiter.key.store(key, Ordering::Release);
+                Ok(())
+            }
+            None => Err(rsp),
+        }
+    }
+
+    /// set rsp for each id in the items
+    /// return the items whose id is no longer valid
+    pub fn set_rsp_many(items: Vec<(ID, T)>) -> Vec<(ID, T)> {
+        let mut failed = Vec::new();
+        for (id, rsp) in items {
+            // keep a copy to report the failed id, it's only used when not delivered
+            // and takes the place of the id handed in, so the same rule applies
+            let copy = unsafe { id.clone_for_retry() };
+            if let Err(rsp) = Self::set_rsp(id, rsp) {
+                failed.push((copy, rsp));
+            }
         }
+        failed
     }
 }
 
@@ -149,6 +495,18 @@ mod tests {
         assert!(waiter.id().is_err());
     }
 
+    #[test]
+    fn token_waiter_try_id() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id = waiter.try_id().unwrap();
+        assert_eq!(waiter.try_id().unwrap_err(), IdError::AlreadyIssued);
+
+        // the id is available again after the rsp is set
+        TokenWaiter::<usize>::set_rsp(id, 42).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+        assert!(waiter.try_id().is_ok());
+    }
+
     #[test]
     fn token_waiter() {
         for j in 0..100 {
@@ -179,11 +537,11 @@ mod tests {
             // trigger the rsp in another coroutine
             let h = go!(move || {
                 may::coroutine::sleep(Duration::from_millis(102));
-                TokenWaiter::set_rsp(id, 42)
+                TokenWaiter::<usize>::set_rsp(id, 42)
             });
             // this will block until the rsp was set
             let ret = waiter.wait_rsp(Duration::from_millis(100));
-            h.join().unwrap();
+            h.join().unwrap().ok();
             ret
         })
         .join()
@@ -191,4 +549,321 @@ mod tests {
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn token_waiter_until() {
+        let result = go!(|| {
+            let waiter = TokenWaiter::<usize>::new();
+            // the deadline is already passed
+            let err = waiter.wait_rsp_until(Instant::now()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+            let id = waiter.id().unwrap();
+            go!(move || TokenWaiter::<usize>::set_rsp(id, 42));
+            waiter.wait_rsp_until(Instant::now() + Duration::from_secs(2))
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[test]
+    fn token_waiter_set_rsp_invalid() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id: usize = waiter.id().unwrap().into();
+
+        let ret = TokenWaiter::<usize>::set_rsp(unsafe { ID::from_usize(id) }, 42);
+        assert_eq!(ret, Ok(()));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+
+        // the id is invalidated after the rsp is delivered
+        let ret = TokenWaiter::<usize>::set_rsp(unsafe { ID::from_usize(id) }, 43);
+        assert_eq!(ret, Err(43));
+    }
+
+    #[test]
+    fn token_waiter_generation() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id: usize = waiter.id().unwrap().into();
+
+        // same address with a different generation
+        let stale = id ^ (1 << TAG_SHIFT);
+        let ret = TokenWaiter::<usize>::set_rsp(unsafe { ID::from_usize(stale) }, 42);
+        assert_eq!(ret, Err(42));
+        assert!(!waiter.waiter.is_ready());
+
+        // the right generation is still valid
+        let ret = TokenWaiter::<usize>::set_rsp(unsafe { ID::from_usize(id) }, 43);
+        assert_eq!(ret, Ok(()));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 43);
+    }
+
+    #[test]
+    fn token_waiter_id_string() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id = waiter.id().unwrap();
+        let s = id.to_string();
+
+        let id = unsafe { ID::parse(&s) }.unwrap();
+        assert_eq!(id.to_string(), s);
+        TokenWaiter::<usize>::set_rsp(id, 42).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+
+        assert_eq!(unsafe { ID::parse("0") }.unwrap_err(), IdError::Invalid);
+        assert_eq!(unsafe { ID::parse("") }.unwrap_err(), IdError::Invalid);
+        assert_eq!(unsafe { ID::parse("xyz") }.unwrap_err(), IdError::Invalid);
+    }
+
+    #[test]
+    fn token_waiter_timeout_reissue() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id: usize = waiter.id().unwrap().into();
+        assert!(waiter.wait_rsp(Duration::from_millis(10)).is_err());
+
+        // the late rsp would not be delivered
+        let ret = TokenWaiter::<usize>::set_rsp(unsafe { ID::from_usize(id) }, 42);
+        assert_eq!(ret, Err(42));
+
+        // the id could be issued again after timeout
+        let id = waiter.id().unwrap();
+        TokenWaiter::<usize>::set_rsp(id, 43).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 43);
+    }
+
+    #[test]
+    fn token_waiter_or_id() {
+        let result = go!(|| {
+            let waiter = TokenWaiter::<usize>::new();
+            let _lost_id = waiter.id().unwrap();
+            // the first attempt timeout, and we get a new id
+            let (e, id) = waiter
+                .wait_rsp_or_id(Duration::from_millis(10))
+                .unwrap_err();
+            assert_eq!(e, WaitError::Timeout);
+
+            go!(move || TokenWaiter::<usize>::set_rsp(id, 42));
+            waiter.wait_rsp_or_id(None).map_err(|(e, _)| e)
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(result, Ok(42));
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn token_waiter_tag_bits() {
+        for bits in [0, 4, MAX_TAG_BITS as u8] {
+            let waiter = TokenWaiter::<usize>::with_tag_bits(bits);
+            let mut ids = std::collections::HashSet::new();
+            for i in 0..1024 {
+                let id = waiter.id().unwrap();
+                ids.insert(id.to_string());
+                TokenWaiter::<usize>::set_rsp(id, i).unwrap();
+                assert_eq!(waiter.wait_rsp(None).unwrap(), i);
+            }
+            // ids on the same address are unique within the tag space
+            if bits == MAX_TAG_BITS as u8 {
+                assert_eq!(ids.len(), 1024);
+            }
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    #[should_panic]
+    fn token_waiter_too_many_tag_bits() {
+        TokenWaiter::<usize>::with_tag_bits(MAX_TAG_BITS as u8 + 1);
+    }
+
+    #[test]
+    fn token_waiter_take_rsp() {
+        let waiter = TokenWaiter::<usize>::new();
+        let (e, id) = waiter
+            .wait_rsp_or_id(Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(e, WaitError::Timeout);
+        assert_eq!(waiter.take_rsp(), None);
+
+        // the rsp arrives late through the new id
+        TokenWaiter::<usize>::set_rsp(id, 42).unwrap();
+        assert_eq!(waiter.take_rsp(), Some(42));
+        assert_eq!(waiter.take_rsp(), None);
+    }
+
+    #[test]
+    fn token_waiter_retry() {
+        let waiter = TokenWaiter::<usize>::new();
+        let _id = waiter.try_id().unwrap();
+
+        let mut retries = 0;
+        let rsp = waiter.wait_rsp_retry(Duration::from_millis(10), 3, |id| {
+            retries += 1;
+            // only the last attempt gets the rsp
+            if retries == 2 {
+                TokenWaiter::<usize>::set_rsp(id, 42).unwrap();
+            }
+        });
+        assert_eq!(rsp.unwrap(), 42);
+        assert_eq!(retries, 2);
+
+        let _id = waiter.try_id().unwrap();
+        let err = waiter
+            .wait_rsp_retry(Duration::from_millis(10), 2, |_id| {})
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // the id is released after the last attempt
+        assert!(waiter.try_id().is_ok());
+    }
+
+    #[test]
+    fn token_waiter_bogus_id() {
+        // the address is null
+        let id = (1 << TAG_SHIFT) | ((MAX_TAG_BITS as usize) << LOCK_BITS);
+        let id = unsafe { ID::from_usize(id) };
+        assert_eq!(TokenWaiter::<usize>::set_rsp(id, 42), Err(42));
+
+        // the tag width is out of range
+        if MAX_TAG_BITS < WIDTH_MASK as u32 {
+            let waiter = TokenWaiter::<usize>::new();
+            let id = usize::from(waiter.try_id().unwrap()) | (WIDTH_MASK << LOCK_BITS);
+            let id = unsafe { ID::from_usize(id) };
+            assert_eq!(TokenWaiter::<usize>::set_rsp(id, 42), Err(42));
+        }
+
+        // the lock bit is set, it matches the key while the waiter is locked
+        let waiter = TokenWaiter::<usize>::new();
+        let id = usize::from(waiter.try_id().unwrap());
+        waiter.key.store(id | 1, Ordering::Relaxed);
+        let bogus = unsafe { ID::from_usize(id | 1) };
+        assert_eq!(TokenWaiter::<usize>::set_rsp(bogus, 42), Err(42));
+        assert_eq!(waiter.key.load(Ordering::Relaxed), id | 1);
+        // the issued id is not affected
+        waiter.key.store(id, Ordering::Relaxed);
+        TokenWaiter::<usize>::set_rsp(unsafe { ID::from_usize(id) }, 43).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 43);
+    }
+
+    #[test]
+    fn token_waiter_stable_id() {
+        let waiter = std::sync::Arc::new(TokenWaiter::<usize>::new_stable_id());
+        let id = usize::from(waiter.try_id().unwrap());
+
+        for i in 0..10 {
+            let id = unsafe { ID::from_usize(id) };
+            go!(move || TokenWaiter::<usize>::set_rsp(id, i).unwrap());
+            assert_eq!(waiter.wait_rsp(None).unwrap(), i);
+            // the id is kept across the rounds
+            assert_eq!(waiter.try_id().unwrap_err(), IdError::AlreadyIssued);
+        }
+
+        // timeout doesn't release the stable id
+        let err = waiter.wait_rsp(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let id_1 = unsafe { ID::from_usize(id) };
+        TokenWaiter::<usize>::set_rsp(id_1, 100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+
+        waiter.release();
+        let id_1 = unsafe { ID::from_usize(id) };
+        assert_eq!(TokenWaiter::<usize>::set_rsp(id_1, 200), Err(200));
+        assert!(waiter.try_id().is_ok());
+    }
+
+    #[test]
+    fn token_waiter_clone_for_retry() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id = waiter.id().unwrap();
+        let retry = unsafe { id.clone_for_retry() };
+
+        assert_eq!(TokenWaiter::<usize>::set_rsp(id, 42), Ok(()));
+        // the copy is stale after the first delivery
+        assert_eq!(TokenWaiter::<usize>::set_rsp(retry, 43), Err(43));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+
+        // any copy of the next id could deliver first, the original is stale then
+        let id = waiter.id().unwrap();
+        let retry = unsafe { id.clone_for_retry() };
+        assert_eq!(TokenWaiter::<usize>::set_rsp(retry, 44), Ok(()));
+        assert_eq!(TokenWaiter::<usize>::set_rsp(id, 45), Err(45));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 44);
+    }
+
+    #[test]
+    fn token_waiter_set_rsp_many() {
+        let waiters: Vec<_> = (0..4).map(|_| TokenWaiter::<usize>::new()).collect();
+        let mut items: Vec<_> = waiters
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (w.id().unwrap(), i))
+            .collect();
+        // invalidate the id of the last waiter
+        let stale = items.pop().unwrap().0;
+        waiters[3].release();
+        items.push((stale, 3));
+
+        let failed = TokenWaiter::set_rsp_many(items);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].1, 3);
+        for (i, waiter) in waiters.iter().take(3).enumerate() {
+            assert_eq!(waiter.wait_rsp(None).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn token_waiter_from_usize_checked() {
+        assert!(unsafe { ID::from_usize_checked(0) }.is_none());
+        // the lock bit is set
+        assert!(unsafe { ID::from_usize_checked(1) }.is_none());
+        // the null address
+        let null = (1 << TAG_SHIFT) | ((MAX_TAG_BITS as usize) << LOCK_BITS);
+        assert!(unsafe { ID::from_usize_checked(null) }.is_none());
+
+        let waiter = TokenWaiter::<usize>::new();
+        let id: usize = waiter.id().unwrap().into();
+        let id = unsafe { ID::from_usize_checked(id) }.unwrap();
+        assert_eq!(TokenWaiter::<usize>::set_rsp(id, 42), Ok(()));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+    }
+
+    #[test]
+    fn token_waiter_release() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id: usize = waiter.id().unwrap().into();
+        assert!(waiter.id().is_err());
+
+        // abandon the id before waiting
+        waiter.release();
+        let ret = TokenWaiter::<usize>::set_rsp(unsafe { ID::from_usize(id) }, 42);
+        assert_eq!(ret, Err(42));
+        assert_eq!(waiter.take_rsp(), None);
+
+        let id = waiter.id().unwrap();
+        assert_eq!(TokenWaiter::<usize>::set_rsp(id, 43), Ok(()));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 43);
+    }
+
+    #[test]
+    fn token_waiter_canceled_panics() {
+        let h = go!(|| {
+            let waiter = TokenWaiter::<usize>::new();
+            let _id = waiter.id().unwrap();
+            waiter.wait_rsp(None)
+        });
+        // make sure the coroutine is parked
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound by the cancel panic
+        assert!(h.join().is_err());
+
+        let h = go!(|| {
+            let waiter = TokenWaiter::<usize>::new();
+            let _id = waiter.id().unwrap();
+            waiter.wait_rsp_or_id(None).map_err(|(e, _id)| e)
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        assert!(h.join().is_err());
+    }
 }
@@ -1,68 +1,1112 @@
-use may::coroutine;
+#[cfg(feature = "futures")]
+use futures::task::AtomicWaker;
+use may::coroutine::{self, ParkError};
 use may::sync::{AtomicOption, Blocker};
 
-use std::time::Duration;
+use crate::deadline_scope::DeadlineScope;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
+/// the reason why a wait doesn't get the rsp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// no rsp arrived before the timeout
+    Timeout,
+    /// the wait is canceled by `cancel_wait`, or the coroutine is canceled
+    Canceled,
+    /// there is no such waiter
+    NotFound,
+    /// the sender is dropped without setting the rsp
+    Disconnected,
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WaitError::Timeout => write!(f, "wait rsp timeout"),
+            WaitError::Canceled => write!(f, "wait rsp canceled"),
+            WaitError::NotFound => write!(f, "wait rsp not found"),
+            WaitError::Disconnected => write!(f, "wait rsp disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for WaitError {}
+
+/// cooperative cancellation for `Waiter::wait_rsp_cancelable`
+///
+/// the clones share the same state, `cancel` wakes up all the waits that
+/// are using the token without canceling the coroutine
+#[derive(Clone)]
+pub struct CancelToken {
+    inner: Arc<CancelInner>,
+}
+
+struct CancelInner {
+    canceled: AtomicBool,
+    // the blockers of the waits that are using the token
+    blockers: Mutex<Vec<Arc<Blocker>>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken {
+            inner: Arc::new(CancelInner {
+                canceled: AtomicBool::new(false),
+                blockers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// trip the token and wake up all the waits that are using it
+    pub fn cancel(&self) {
+        self.inner.canceled.store(true, Ordering::Release);
+        for blocker in self.inner.blockers.lock().unwrap().iter() {
+            blocker.unpark();
+        }
+    }
+
+    fn add_blocker(&self, blocker: &Arc<Blocker>) {
+        self.inner.blockers.lock().unwrap().push(blocker.clone());
+    }
+
+    // remove the blocker of the finished wait, the other waits are kept
+    fn remove_blocker(&self, blocker: &Arc<Blocker>) {
+        let mut blockers = self.inner.blockers.lock().unwrap();
+        if let Some(i) = blockers.iter().position(|b| Arc::ptr_eq(b, blocker)) {
+            blockers.swap_remove(i);
+        }
+    }
+
+    /// check if the token is tripped
+    pub fn is_canceled(&self) -> bool {
+        self.inner.canceled.load(Ordering::Acquire)
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken::new()
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("canceled", &self.is_canceled())
+            .finish()
+    }
+}
+
+impl From<WaitError> for io::Error {
+    fn from(e: WaitError) -> Self {
+        let kind = match e {
+            WaitError::Timeout => io::ErrorKind::TimedOut,
+            WaitError::Canceled => io::ErrorKind::Interrupted,
+            WaitError::NotFound => io::ErrorKind::NotFound,
+            WaitError::Disconnected => io::ErrorKind::BrokenPipe,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
 /// Generic Waiter that could wait for a response
-pub struct Waiter<T> {
+///
+/// it parks the coroutine, use `ThreadWaiter` to wait from plain threads.
+/// a rsp that is never taken is dropped together with the waiter, with the
+/// `debug` feature a warning is printed for it
+pub struct Waiter<T, M = ()> {
     blocker: Blocker,
     rsp: AtomicOption<Box<T>>,
+    // set while the rsp is held, so that it could be checked without taking it
+    ready: AtomicBool,
+    // serialize the access to the rsp so that `ready` always tracks it
+    set_lock: AtomicBool,
+    // set by cancel_wait, so that the wait could tell it from a stale wake up
+    canceled: AtomicBool,
+    // the number of the coroutines parked on the waiter
+    parked: AtomicUsize,
+    // extra blockers that waiting on a group of waiters
+    observers: Mutex<Vec<Arc<Blocker>>>,
+    // called for each delivered rsp
+    on_set: Option<Box<dyn Fn() + Send + Sync>>,
+    // the number of set_rsp and cancel_wait
+    #[cfg(feature = "debug")]
+    wakeups: std::sync::atomic::AtomicUsize,
+    // the async task that polling the waiter
+    #[cfg(feature = "futures")]
+    waker: AtomicWaker,
+    // the user data attached to the waiter
+    meta: M,
 }
 
 impl<T> Waiter<T> {
     pub fn new() -> Self {
+        Waiter::with_meta(())
+    }
+
+    /// create a waiter that already holds the rsp, the wait would return at once
+    pub fn new_with_rsp(rsp: T) -> Self {
+        let waiter = Waiter::new();
+        waiter.rsp.store(Box::new(rsp));
+        waiter.ready.store(true, Ordering::Relaxed);
+        waiter.blocker.unpark();
+        waiter
+    }
+
+    /// create a waiter that calls `f` each time a rsp is delivered
+    ///
+    /// the callback is called after the rsp is stored and before the waiter
+    /// is waked up, it's not called by `cancel_wait`
+    pub fn with_on_set<F: Fn() + Send + Sync + 'static>(f: F) -> Self {
+        let mut waiter = Waiter::new();
+        waiter.on_set = Some(Box::new(f));
+        waiter
+    }
+}
+
+impl<T, M> Waiter<T, M> {
+    /// create a waiter that carries the metadata, e.g. the context of the request
+    pub fn with_meta(meta: M) -> Self {
         Waiter {
-            blocker: Blocker::new(false),
+            // the wait decides how to deal with the coroutine cancel
+            blocker: Blocker::new(true),
             rsp: AtomicOption::none(),
+            ready: AtomicBool::new(false),
+            set_lock: AtomicBool::new(false),
+            canceled: AtomicBool::new(false),
+            parked: AtomicUsize::new(0),
+            observers: Mutex::new(Vec::new()),
+            on_set: None,
+            #[cfg(feature = "debug")]
+            wakeups: std::sync::atomic::AtomicUsize::new(0),
+            #[cfg(feature = "futures")]
+            waker: AtomicWaker::new(),
+            meta,
+        }
+    }
+
+    /// get the metadata attached to the waiter
+    pub fn meta(&self) -> &M {
+        &self.meta
+    }
+
+    fn lock_set(&self) {
+        while self
+            .set_lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+    }
+
+    fn unlock_set(&self) {
+        self.set_lock.store(false, Ordering::Release);
+    }
+
+    // take the rsp and clear the ready flag along with it
+    fn take_boxed(&self) -> Option<Box<T>> {
+        self.lock_set();
+        let rsp = self.rsp.take();
+        self.ready.store(false, Ordering::Relaxed);
+        self.unlock_set();
+        rsp
+    }
+
+    fn wake(&self) {
+        if let Some(on_set) = self.on_set.as_ref() {
+            on_set();
         }
+        #[cfg(feature = "debug")]
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+        self.blocker.unpark();
+        self.wake_observers();
+        #[cfg(feature = "futures")]
+        self.waker.wake();
+    }
+
+    /// get the blocker that the waiter parks on, to compose custom wait logic
+    ///
+    /// `set_rsp` stores the rsp before it unparks the blocker, so after the park
+    /// returns the rsp should be got by `try_wait_rsp`. an unpark without rsp
+    /// is taken as a stale wake up by `wait_rsp`, which would park again.
+    /// the park returns `ParkError::Canceled` when the coroutine is canceled
+    pub fn blocker(&self) -> &Blocker {
+        &self.blocker
+    }
+
+    // register an extra blocker that would be waked up by each rsp and cancel
+    pub(crate) fn add_observer(&self, blocker: &Arc<Blocker>) {
+        self.observers.lock().unwrap().push(blocker.clone());
     }
 
-    pub fn set_rsp(&self, rsp: T) {
+    // remove the registration of the blocker, the others are kept
+    pub(crate) fn remove_observer(&self, blocker: &Arc<Blocker>) {
+        let mut observers = self.observers.lock().unwrap();
+        if let Some(i) = observers.iter().position(|b| Arc::ptr_eq(b, blocker)) {
+            observers.swap_remove(i);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn observers(&self) -> usize {
+        self.observers.lock().unwrap().len()
+    }
+
+    fn wake_observers(&self) {
+        for observer in self.observers.lock().unwrap().iter() {
+            observer.unpark();
+        }
+    }
+
+    // check if the waiter is canceled without consuming the cancel
+    pub(crate) fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::Acquire)
+    }
+
+    /// set the rsp and wake up the waiter
+    /// return the previous rsp if it was not consumed yet
+    pub fn set_rsp(&self, rsp: T) -> Option<T> {
         // set the response
+        self.lock_set();
+        let old = self.rsp.take();
         self.rsp.store(Box::new(rsp));
+        self.ready.store(true, Ordering::Relaxed);
+        self.unlock_set();
         // wake up the blocker
-        self.blocker.unpark();
+        self.wake();
+        old.map(|rsp| *rsp)
+    }
+
+    /// set the rsp only when there is no rsp held by the waiter
+    /// return the rsp back if the waiter already has one
+    pub fn set_rsp_if_empty(&self, rsp: T) -> Result<(), T> {
+        self.lock_set();
+        if self.ready.load(Ordering::Relaxed) {
+            self.unlock_set();
+            return Err(rsp);
+        }
+        self.rsp.store(Box::new(rsp));
+        self.ready.store(true, Ordering::Relaxed);
+        self.unlock_set();
+        // wake up the blocker
+        self.wake();
+        Ok(())
     }
 
     pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
-        use may::coroutine::ParkError;
-        use std::io::{Error, ErrorKind};
-        let timeout = timeout.into();
+        self.wait_rsp_boxed(timeout.into(), true)
+            .map(|rsp| *rsp)
+            .map_err(io::Error::from)
+    }
+
+    /// wait for response without timeout
+    pub fn wait_rsp_blocking(&self) -> io::Result<T> {
+        self.wait_rsp(None)
+    }
+
+    /// wait for response for at most `timeout`
+    pub fn wait_rsp_timeout(&self, timeout: Duration) -> io::Result<T> {
+        self.wait_rsp(timeout)
+    }
+
+    /// wait for the rsp and report why it failed with `WaitError`
+    ///
+    /// a canceled coroutine gets `Canceled` instead of the cancel panic, it
+    /// would still be unwound by its next blocking call
+    pub fn wait_rsp_result<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<T, WaitError> {
+        self.wait_rsp_boxed(timeout.into(), false).map(|rsp| *rsp)
+    }
+
+    /// check the rsp `spin_iters` times before parking
+    ///
+    /// this saves the park and unpark when the rsp usually arrives in a very
+    /// short time, the spinning is not counted in the timeout
+    pub fn wait_rsp_spin<D: Into<Option<Duration>>>(
+        &self,
+        timeout: D,
+        spin_iters: usize,
+    ) -> io::Result<T> {
+        for _ in 0..spin_iters {
+            if let Some(rsp) = self.take_boxed() {
+                // the wake up left by the rsp would be ignored by the next wait
+                return Ok(*rsp);
+            }
+            std::hint::spin_loop();
+        }
+        self.wait_rsp(timeout)
+    }
+
+    /// wait for the rsp and call `f` with a reference to it, the rsp is dropped after that
+    ///
+    /// this would not move the rsp out of its box, which is cheaper for a large `T`
+    pub fn wait_rsp_with<R, D, F>(&self, timeout: D, f: F) -> io::Result<R>
+    where
+        D: Into<Option<Duration>>,
+        F: FnOnce(&T) -> R,
+    {
+        let rsp = self.wait_rsp_boxed(timeout.into(), true)?;
+        Ok(f(&rsp))
+    }
+
+    // the coroutine cancel panics if `cancel_panic` is true, else returns `Canceled`
+    pub(crate) fn wait_rsp_boxed(
+        &self,
+        timeout: Option<Duration>,
+        cancel_panic: bool,
+    ) -> Result<Box<T>, WaitError> {
+        let deadline = timeout.map(|d| Instant::now() + d);
         loop {
-            match self.blocker.park(timeout) {
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            match self.park(&self.blocker, timeout) {
                 Ok(_) => {
-                    if let Some(rsp) = self.rsp.take() {
-                        return Ok(*rsp);
-                        // None => Err(Error::new(ErrorKind::Other, "unable to get the rsp")),
-                        // false wake up try again
-                        // None => {}
+                    if let Some(rsp) = self.take_boxed() {
+                        return Ok(rsp);
                     }
+                    // waked up by cancel_wait without rsp
+                    if self.canceled.swap(false, Ordering::AcqRel) {
+                        return Err(WaitError::Canceled);
+                    }
+                    // the wake up left by a rsp that is already taken, wait again
                 }
-                Err(ParkError::Timeout) => {
-                    return Err(Error::new(ErrorKind::TimedOut, "wait rsp timeout"))
-                }
-                Err(ParkError::Canceled) => {
-                    coroutine::trigger_cancel_panic();
+                Err(ParkError::Timeout) => return Err(WaitError::Timeout),
+                Err(ParkError::Canceled) if cancel_panic => coroutine::trigger_cancel_panic(),
+                Err(ParkError::Canceled) => return Err(WaitError::Canceled),
+            }
+        }
+    }
+
+    /// wait for the rsp, return `Canceled` once the `token` is tripped
+    ///
+    /// the token could be shared by several waits, `cancel` wakes up all of
+    /// them. a canceled coroutine also gets `Canceled` instead of the cancel panic
+    pub fn wait_rsp_cancelable<D: Into<Option<Duration>>>(
+        &self,
+        timeout: D,
+        token: &CancelToken,
+    ) -> Result<T, WaitError> {
+        let deadline = timeout.into().map(|d| Instant::now() + d);
+        // park on a blocker that both the rsp and the token could wake up,
+        // the coroutine cancel is returned so that the registrations are removed
+        let blocker = Arc::new(Blocker::new(true));
+        token.add_blocker(&blocker);
+        self.add_observer(&blocker);
+        let ret = loop {
+            // check after registering so that no wake up is lost
+            if let Some(rsp) = self.take_boxed() {
+                break Ok(*rsp);
+            }
+            if token.is_canceled() || self.canceled.swap(false, Ordering::AcqRel) {
+                break Err(WaitError::Canceled);
+            }
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            match self.park(&blocker, timeout) {
+                Ok(_) => {}
+                Err(ParkError::Timeout) => break Err(WaitError::Timeout),
+                Err(ParkError::Canceled) => break Err(WaitError::Canceled),
+            }
+        };
+        self.remove_observer(&blocker);
+        token.remove_blocker(&blocker);
+        ret
+    }
+
+    /// wait for the rsp in slices, `tick` is called between the slices
+    ///
+    /// the wait is canceled once `tick` returns false, and times out after
+    /// `total` if given, the rsp is not consumed by `tick`
+    pub fn wait_rsp_polled<F: FnMut() -> bool>(
+        &self,
+        slice: Duration,
+        total: Option<Duration>,
+        mut tick: F,
+    ) -> io::Result<T> {
+        let deadline = total.map(|d| Instant::now() + d);
+        loop {
+            let timeout = match deadline {
+                Some(d) => slice.min(d.saturating_duration_since(Instant::now())),
+                None => slice,
+            };
+            match self.wait_rsp_boxed(Some(timeout), true) {
+                Ok(rsp) => return Ok(*rsp),
+                Err(WaitError::Timeout) => {
+                    if deadline.is_some_and(|d| Instant::now() >= d) {
+                        return Err(WaitError::Timeout.into());
+                    }
+                    if !tick() {
+                        return Err(WaitError::Canceled.into());
+                    }
                 }
+                Err(e) => return Err(e.into()),
             }
         }
     }
 
+    /// wait for the rsp, return it along with how long the wait took
+    pub fn wait_rsp_timed(&self, timeout: Option<Duration>) -> io::Result<(T, Duration)> {
+        let start = Instant::now();
+        let rsp = self.wait_rsp(timeout)?;
+        Ok((rsp, start.elapsed()))
+    }
+
+    /// wait for the rsp and convert it with `f`
+    pub fn wait_map<U, D, F>(&self, timeout: D, f: F) -> io::Result<U>
+    where
+        D: Into<Option<Duration>>,
+        F: FnOnce(T) -> U,
+    {
+        self.wait_rsp(timeout).map(f)
+    }
+
+    /// wait for the rsp until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> io::Result<T> {
+        let now = Instant::now();
+        if deadline <= now {
+            return Err(WaitError::Timeout.into());
+        }
+        self.wait_rsp(deadline - now)
+    }
+
+    // park on the blocker and count the waiter as parked meanwhile
+    fn park(&self, blocker: &Blocker, timeout: Option<Duration>) -> Result<(), ParkError> {
+        self.parked.fetch_add(1, Ordering::AcqRel);
+        let ret = blocker.park(timeout);
+        self.parked.fetch_sub(1, Ordering::AcqRel);
+        ret
+    }
+
+    /// check if a coroutine is waiting on the waiter
+    ///
+    /// this covers both the coroutines parked on the waiter itself and the
+    /// ones in a wait over a set of waiters, like `WaiterMap::wait_any`,
+    /// `WaiterSlab::wait_all` and `WaiterGroup::wait_all`
+    pub fn is_parked(&self) -> bool {
+        self.parked.load(Ordering::Acquire) > 0 || !self.observers.lock().unwrap().is_empty()
+    }
+
+    /// wait for the rsp until the deadline of the ambient `DeadlineScope`
+    ///
+    /// wait without timeout if there is no such scope
+    pub fn wait_rsp_scoped(&self) -> io::Result<T> {
+        match DeadlineScope::current() {
+            Some(deadline) => self.wait_rsp_until(deadline),
+            None => self.wait_rsp(None),
+        }
+    }
+
+    /// check if there is a rsp set but not yet taken
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// try to get the rsp without blocking
+    pub fn try_wait_rsp(&self) -> Option<T> {
+        self.take_boxed().map(|rsp| *rsp)
+    }
+
+    /// take the rsp that arrived after the wait returned, e.g. after a timeout
+    ///
+    /// this is the same as `try_wait_rsp`
+    pub fn take_rsp(&self) -> Option<T> {
+        self.try_wait_rsp()
+    }
+
+    /// clear the left rsp and pending wake up so that the waiter could be reused
+    ///
+    /// this must only be called when no other coroutine is waiting on it
+    pub fn reset(&self) {
+        self.take_boxed();
+        self.canceled.store(false, Ordering::Release);
+        // consume the pending unpark, so that the next wait would block again
+        let _ = self.blocker.park(Some(Duration::from_millis(0)));
+    }
+
+    /// the number of wake ups by `set_rsp` and `cancel_wait`
+    #[cfg(feature = "debug")]
+    pub fn wakeups(&self) -> usize {
+        self.wakeups.load(Ordering::Relaxed)
+    }
+
     pub fn cancel_wait(&self) {
+        #[cfg(feature = "debug")]
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+        self.canceled.store(true, Ordering::Release);
         // wake up the blocker without rsp
-        self.blocker.unpark()
+        self.blocker.unpark();
+        self.wake_observers();
+    }
+}
+
+/// the future would only be ready when a rsp is set, `cancel_wait` has no effect on it
+#[cfg(feature = "futures")]
+impl<T, M> std::future::Future for &Waiter<T, M> {
+    type Output = T;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<T> {
+        use std::task::Poll;
+        if let Some(rsp) = self.take_boxed() {
+            return Poll::Ready(*rsp);
+        }
+        self.waker.register(cx.waker());
+        // check again in case the rsp is set before the waker registered
+        match self.take_boxed() {
+            Some(rsp) => Poll::Ready(*rsp),
+            None => Poll::Pending,
+        }
     }
 }
 
-impl<T> fmt::Debug for Waiter<T> {
+impl<T, M> fmt::Debug for Waiter<T, M> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Waiter{{ ... }}")
     }
 }
 
-impl<T> Default for Waiter<T> {
+// the number of waiters dropped with an unconsumed rsp
+#[cfg(feature = "debug")]
+static UNCONSUMED_DROPS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+// called with the rsp type name for each waiter dropped with an unconsumed rsp
+#[cfg(feature = "debug")]
+static UNCONSUMED_DROP_HOOK: std::sync::RwLock<Option<fn(&'static str)>> =
+    std::sync::RwLock::new(None);
+
+/// the number of waiters that are dropped with an unconsumed rsp
+#[cfg(feature = "debug")]
+pub fn unconsumed_drops() -> usize {
+    UNCONSUMED_DROPS.load(Ordering::Relaxed)
+}
+
+/// set the hook that is called with the rsp type name for each waiter that
+/// is dropped with an unconsumed rsp, e.g. to forward it to a logger
+///
+/// nothing is written by the crate itself, there is no hook by default
+#[cfg(feature = "debug")]
+pub fn set_unconsumed_drop_hook(hook: Option<fn(&'static str)>) {
+    *UNCONSUMED_DROP_HOOK.write().unwrap() = hook;
+}
+
+#[cfg(feature = "debug")]
+impl<T, M> Drop for Waiter<T, M> {
+    fn drop(&mut self) {
+        if self.is_ready() {
+            UNCONSUMED_DROPS.fetch_add(1, Ordering::Relaxed);
+            if let Some(hook) = *UNCONSUMED_DROP_HOOK.read().unwrap() {
+                hook(std::any::type_name::<T>());
+            }
+        }
+    }
+}
+
+impl<T, M: Default> Default for Waiter<T, M> {
     fn default() -> Self {
-        Waiter::new()
+        Waiter::with_meta(M::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+    use std::sync::Arc;
+
+    #[test]
+    fn cancel_wait() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+
+        // cancel the wait in another coroutine
+        go!(move || waiter_1.cancel_wait());
+
+        // this will return an error instead of panic
+        let err = waiter.wait_rsp(None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn wait_rsp_result() {
+        let waiter = Waiter::<usize>::new();
+        let ret = waiter.wait_rsp_result(Duration::from_millis(10));
+        assert_eq!(ret, Err(WaitError::Timeout));
+
+        waiter.cancel_wait();
+        assert_eq!(waiter.wait_rsp_result(None), Err(WaitError::Canceled));
+
+        waiter.set_rsp(42);
+        assert_eq!(waiter.wait_rsp_result(None), Ok(42));
+
+        // the wake up left by a taken rsp is not a cancel
+        waiter.set_rsp(43);
+        assert_eq!(waiter.take_rsp(), Some(43));
+        let ret = waiter.wait_rsp_result(Duration::from_millis(10));
+        assert_eq!(ret, Err(WaitError::Timeout));
+    }
+
+    #[test]
+    fn wait_rsp_result_canceled() {
+        let h = go!(|| {
+            let waiter = Waiter::<usize>::new();
+            waiter.wait_rsp_result(None)
+        });
+        // make sure the coroutine is parked
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the wait returns the error instead of unwinding the coroutine
+        assert_eq!(h.join().unwrap(), Err(WaitError::Canceled));
+    }
+
+    #[test]
+    fn wait_rsp_canceled_panics() {
+        let h = go!(|| {
+            let waiter = Waiter::<usize>::new();
+            waiter.wait_rsp(None)
+        });
+        // make sure the coroutine is parked
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound by the cancel panic
+        assert!(h.join().is_err());
+    }
+
+    #[test]
+    fn wait_rsp_until() {
+        let waiter = Waiter::<usize>::new();
+        // the deadline is already passed
+        let err = waiter.wait_rsp_until(Instant::now()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+        go!(move || waiter_1.set_rsp(42));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        assert_eq!(waiter.wait_rsp_until(deadline).unwrap(), 42);
+    }
+
+    #[test]
+    fn try_wait_rsp() {
+        let waiter = Waiter::<usize>::new();
+        assert_eq!(waiter.try_wait_rsp(), None);
+
+        waiter.set_rsp(42);
+        assert_eq!(waiter.try_wait_rsp(), Some(42));
+        // the rsp is consumed
+        assert_eq!(waiter.try_wait_rsp(), None);
+    }
+
+    #[test]
+    fn is_ready() {
+        let waiter = Waiter::<usize>::new();
+        assert!(!waiter.is_ready());
+
+        waiter.set_rsp(42);
+        assert!(waiter.is_ready());
+        // check again would not consume the rsp
+        assert!(waiter.is_ready());
+
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+        assert!(!waiter.is_ready());
+    }
+
+    #[test]
+    fn set_rsp_overwrite() {
+        let waiter = Waiter::<usize>::new();
+        assert_eq!(waiter.set_rsp(1), None);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 1);
+
+        assert_eq!(waiter.set_rsp(2), None);
+        // the first rsp is not consumed
+        assert_eq!(waiter.set_rsp(3), Some(2));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 3);
+    }
+
+    #[test]
+    fn reset() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        waiter.set_rsp(1);
+        assert_eq!(waiter.try_wait_rsp(), Some(1));
+
+        waiter.reset();
+        // the left wake up is cleared
+        let ret = waiter.wait_rsp_result(Duration::from_millis(10));
+        assert_eq!(ret, Err(WaitError::Timeout));
+
+        let waiter_1 = waiter.clone();
+        go!(move || waiter_1.set_rsp(2));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 2);
+    }
+
+    #[test]
+    fn set_rsp_if_empty() {
+        let waiter = Waiter::<usize>::new();
+        assert!(waiter.set_rsp_if_empty(1).is_ok());
+        assert_eq!(waiter.set_rsp_if_empty(2), Err(2));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 1);
+        // the waiter is empty again after the rsp consumed
+        assert!(waiter.set_rsp_if_empty(3).is_ok());
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 3);
+    }
+
+    #[test]
+    fn set_rsp_if_empty_race() {
+        for _ in 0..100 {
+            let waiter = Arc::new(Waiter::<usize>::new());
+            let handles: Vec<_> = (0..2)
+                .map(|i| {
+                    let waiter = waiter.clone();
+                    go!(move || waiter.set_rsp_if_empty(i).is_ok())
+                })
+                .collect();
+            let succeeded = handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .filter(|ok| *ok)
+                .count();
+            // only the first responder wins
+            assert_eq!(succeeded, 1);
+            assert!(waiter.wait_rsp(None).unwrap() < 2);
+        }
+    }
+
+    #[test]
+    fn with_on_set() {
+        use std::sync::atomic::AtomicUsize;
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_1 = count.clone();
+        let waiter = Waiter::<usize>::with_on_set(move || {
+            count_1.fetch_add(1, Ordering::Relaxed);
+        });
+
+        waiter.set_rsp(1);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        waiter.set_rsp(2);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 2);
+
+        // rejected rsp is not delivered
+        waiter.set_rsp_if_empty(3).unwrap();
+        assert_eq!(waiter.set_rsp_if_empty(4), Err(4));
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+
+        waiter.cancel_wait();
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn wait_async() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+
+        // trigger the rsp in another thread
+        let h = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            waiter_1.set_rsp(42);
+        });
+
+        let rsp = futures::executor::block_on(&*waiter);
+        h.join().unwrap();
+        assert_eq!(rsp, 42);
+    }
+
+    #[test]
+    fn take_rsp() {
+        let waiter = Waiter::<usize>::new();
+        let ret = waiter.wait_rsp_result(Duration::from_millis(10));
+        assert_eq!(ret, Err(WaitError::Timeout));
+
+        // the rsp arrives late
+        waiter.set_rsp(42);
+        assert_eq!(waiter.take_rsp(), Some(42));
+        assert_eq!(waiter.take_rsp(), None);
+    }
+
+    #[test]
+    fn new_with_rsp() {
+        let waiter = Waiter::new_with_rsp(42usize);
+        assert!(waiter.is_ready());
+        assert_eq!(waiter.wait_rsp(Duration::from_millis(0)).unwrap(), 42);
+
+        // the waiter works as usual after that
+        let ret = waiter.wait_rsp_result(Duration::from_millis(10));
+        assert_eq!(ret, Err(WaitError::Timeout));
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn wakeups() {
+        let waiter = Waiter::<usize>::new();
+        assert_eq!(waiter.wakeups(), 0);
+
+        waiter.set_rsp(1);
+        assert_eq!(waiter.wakeups(), 1);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 1);
+        assert_eq!(waiter.wakeups(), 1);
+
+        // the rejected rsp doesn't wake up the waiter
+        waiter.set_rsp_if_empty(2).unwrap();
+        assert_eq!(waiter.set_rsp_if_empty(3), Err(3));
+        assert_eq!(waiter.wakeups(), 2);
+
+        waiter.cancel_wait();
+        assert_eq!(waiter.wakeups(), 3);
+    }
+
+    #[test]
+    fn wait_map() {
+        let waiter = Waiter::<usize>::new();
+        let err = waiter
+            .wait_map(Duration::from_millis(10), |rsp| rsp.to_string())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        waiter.set_rsp(42);
+        let rsp = waiter.wait_map(None, |rsp| rsp.to_string()).unwrap();
+        assert_eq!(rsp, "42");
+    }
+
+    #[test]
+    fn wait_rsp_with() {
+        let waiter = Waiter::<Vec<usize>>::new();
+        waiter.set_rsp(vec![1, 2, 3]);
+        let sum = waiter.wait_rsp_with(None, |rsp| rsp.iter().sum::<usize>());
+        assert_eq!(sum.unwrap(), 6);
+        // the rsp is consumed
+        assert!(!waiter.is_ready());
+    }
+
+    #[test]
+    fn with_meta() {
+        let waiter = Waiter::<usize, &str>::with_meta("echo");
+        waiter.set_rsp(42);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+        assert_eq!(*waiter.meta(), "echo");
+    }
+
+    #[test]
+    fn wait_rsp_spin() {
+        let waiter = Waiter::<usize>::new();
+        waiter.set_rsp(42);
+        assert_eq!(waiter.wait_rsp_spin(None, 100).unwrap(), 42);
+
+        // fall back to park after spinning
+        let err = waiter
+            .wait_rsp_spin(Duration::from_millis(10), 100)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let waiter = Arc::new(waiter);
+        let waiter_1 = waiter.clone();
+        go!(move || waiter_1.set_rsp(43));
+        assert_eq!(waiter.wait_rsp_spin(None, 1000).unwrap(), 43);
+    }
+
+    #[test]
+    fn wait_rsp_cancelable() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let token = CancelToken::new();
+
+        // trip the token in another coroutine
+        let token_1 = token.clone();
+        go!(move || {
+            may::coroutine::sleep(Duration::from_millis(10));
+            token_1.cancel();
+        });
+        let ret = waiter.wait_rsp_cancelable(None, &token);
+        assert_eq!(ret, Err(WaitError::Canceled));
+        assert!(token.is_canceled());
+
+        // the rsp is still delivered with a fresh token
+        let waiter_1 = waiter.clone();
+        go!(move || waiter_1.set_rsp(42));
+        let ret = waiter.wait_rsp_cancelable(None, &CancelToken::new());
+        assert_eq!(ret, Ok(42));
+
+        let ret = waiter.wait_rsp_cancelable(Duration::from_millis(10), &CancelToken::new());
+        assert_eq!(ret, Err(WaitError::Timeout));
+
+        // cancel_wait also wakes up the wait
+        let waiter_1 = waiter.clone();
+        go!(move || {
+            may::coroutine::sleep(Duration::from_millis(10));
+            waiter_1.cancel_wait();
+        });
+        let ret = waiter.wait_rsp_cancelable(None, &CancelToken::new());
+        assert_eq!(ret, Err(WaitError::Canceled));
+    }
+
+    #[test]
+    fn is_parked_concurrent_waits() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let tokens = [CancelToken::new(), CancelToken::new()];
+        let handles: Vec<_> = tokens
+            .iter()
+            .map(|token| {
+                let (waiter, token) = (waiter.clone(), token.clone());
+                go!(move || waiter.wait_rsp_cancelable(None, &token))
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(waiter.parked.load(Ordering::Acquire), 2);
+
+        // the first wait returns, the other one is still parked
+        let mut handles = handles.into_iter();
+        tokens[0].cancel();
+        assert_eq!(
+            handles.next().unwrap().join().unwrap(),
+            Err(WaitError::Canceled)
+        );
+        assert!(waiter.is_parked());
+
+        tokens[1].cancel();
+        assert_eq!(
+            handles.next().unwrap().join().unwrap(),
+            Err(WaitError::Canceled)
+        );
+        assert!(!waiter.is_parked());
+    }
+
+    #[test]
+    fn wait_rsp_cancelable_shared_token() {
+        let token = CancelToken::new();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let token = token.clone();
+                go!(move || Waiter::<usize>::new().wait_rsp_cancelable(None, &token))
+            })
+            .collect();
+        // make sure both waits are parked
+        std::thread::sleep(Duration::from_millis(50));
+        // both waits are woken up
+        token.cancel();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), Err(WaitError::Canceled));
+        }
+        assert!(token.inner.blockers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wait_rsp_cancelable_coroutine_canceled() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let token = CancelToken::new();
+        let (waiter_1, token_1) = (waiter.clone(), token.clone());
+        let h = go!(move || waiter_1.wait_rsp_cancelable(None, &token_1));
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the cancel is returned and the registrations are removed
+        assert_eq!(h.join().unwrap(), Err(WaitError::Canceled));
+        assert!(waiter.observers.lock().unwrap().is_empty());
+        assert!(token.inner.blockers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wait_rsp_polled() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+        go!(move || {
+            may::coroutine::sleep(Duration::from_millis(100));
+            waiter_1.set_rsp(42);
+        });
+
+        let mut ticks = 0;
+        let slice = Duration::from_millis(10);
+        let rsp = waiter.wait_rsp_polled(slice, None, || {
+            ticks += 1;
+            true
+        });
+        assert_eq!(rsp.unwrap(), 42);
+        assert!(ticks >= 3);
+
+        // abort by the tick
+        let err = waiter.wait_rsp_polled(slice, None, || false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+
+        let total = Some(Duration::from_millis(30));
+        let err = waiter.wait_rsp_polled(slice, total, || true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn drop_unconsumed_rsp() {
+        use std::sync::atomic::AtomicUsize;
+        static HOOKED: AtomicUsize = AtomicUsize::new(0);
+        set_unconsumed_drop_hook(Some(|_name| {
+            HOOKED.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        let before = unconsumed_drops();
+        let waiter = Waiter::<usize>::new();
+        waiter.set_rsp(42);
+        drop(waiter);
+        assert!(unconsumed_drops() > before);
+        assert!(HOOKED.load(Ordering::Relaxed) > 0);
+        set_unconsumed_drop_hook(None);
+    }
+
+    #[test]
+    fn wait_rsp_blocking() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+        go!(move || waiter_1.set_rsp(42));
+        assert_eq!(waiter.wait_rsp_blocking().unwrap(), 42);
+
+        let err = waiter
+            .wait_rsp_timeout(Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        waiter.set_rsp(43);
+        assert_eq!(waiter.wait_rsp_timeout(Duration::from_secs(1)).unwrap(), 43);
+    }
+
+    #[test]
+    fn wait_rsp_scoped() {
+        let waiter = Waiter::<usize>::new();
+        waiter.set_rsp(42);
+        assert_eq!(waiter.wait_rsp_scoped().unwrap(), 42);
+
+        let _scope = DeadlineScope::new(Duration::from_millis(10));
+        let start = Instant::now();
+        let err = waiter.wait_rsp_scoped().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn wait_rsp_timed() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+        let delay = Duration::from_millis(20);
+        go!(move || {
+            may::coroutine::sleep(delay);
+            waiter_1.set_rsp(42);
+        });
+        let (rsp, elapsed) = waiter.wait_rsp_timed(None).unwrap();
+        assert_eq!(rsp, 42);
+        assert!(elapsed >= delay);
+    }
+
+    #[test]
+    fn park_on_blocker() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let waiter_1 = waiter.clone();
+        go!(move || waiter_1.set_rsp(42));
+
+        // park on the blocker directly and take the rsp after that
+        let rsp = loop {
+            waiter.blocker().park(None).unwrap();
+            if let Some(rsp) = waiter.try_wait_rsp() {
+                break rsp;
+            }
+        };
+        assert_eq!(rsp, 42);
+
+        // a manual unpark is ignored by the wait
+        waiter.blocker().unpark();
+        let err = waiter.wait_rsp(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
     }
 }
@@ -0,0 +1,187 @@
+use may::coroutine::{self, ParkError};
+use may::sync::Blocker;
+
+use crate::waiter::{WaitError, Waiter};
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// the setter of a member in the `WaiterGroup`
+pub struct GroupSetter<T> {
+    waiter: Arc<Waiter<T>>,
+}
+
+impl<T> GroupSetter<T> {
+    /// set rsp for the member
+    /// return the previous rsp if it was not consumed yet
+    pub fn set_rsp(&self, rsp: T) -> Option<T> {
+        self.waiter.set_rsp(rsp)
+    }
+}
+
+impl<T> std::fmt::Debug for GroupSetter<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "GroupSetter{{ ... }}")
+    }
+}
+
+/// Waiter group that could wait for all the members to get the response
+pub struct WaiterGroup<T> {
+    members: Vec<Arc<Waiter<T>>>,
+}
+
+impl<T> std::fmt::Debug for WaiterGroup<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WaiterGroup{{ members: {} }}", self.members.len())
+    }
+}
+
+impl<T> Default for WaiterGroup<T> {
+    fn default() -> Self {
+        WaiterGroup::new()
+    }
+}
+
+impl<T> WaiterGroup<T> {
+    pub fn new() -> Self {
+        WaiterGroup {
+            members: Vec::new(),
+        }
+    }
+
+    /// add a member to the group, return the setter for it
+    pub fn add(&mut self) -> GroupSetter<T> {
+        let waiter = Arc::new(Waiter::new());
+        self.members.push(waiter.clone());
+        GroupSetter { waiter }
+    }
+
+    /// the number of members in the group
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// return true if there is no member in the group
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// wait for all the members to get the rsp, the rsps are in the add order
+    ///
+    /// if timeout, the error reports the index of the missing members, and
+    /// the received rsps are kept so that we could wait again
+    pub fn wait_all<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<Vec<T>> {
+        let deadline = timeout.into().map(|d| Instant::now() + d);
+        // register one blocker on all the members, so that any rsp would wake us up
+        // the coroutine cancel is returned by the park so that we could unregister
+        let blocker = Arc::new(Blocker::new(true));
+        for waiter in self.members.iter() {
+            waiter.add_observer(&blocker);
+        }
+
+        let mut cancel_panic = false;
+        let ret = loop {
+            if self.members.iter().all(|w| w.is_ready()) {
+                break Ok(());
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        break Err(WaitError::Timeout);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+
+            match blocker.park(timeout) {
+                Ok(_) => {}
+                Err(ParkError::Timeout) => break Err(WaitError::Timeout),
+                Err(ParkError::Canceled) => {
+                    cancel_panic = true;
+                    break Err(WaitError::Canceled);
+                }
+            }
+        };
+
+        for waiter in self.members.iter() {
+            waiter.remove_observer(&blocker);
+        }
+        // the coroutine cancel unwinds like `Waiter::wait_rsp`
+        if cancel_panic {
+            coroutine::trigger_cancel_panic();
+        }
+
+        match ret {
+            Ok(()) => Ok(self
+                .members
+                .iter()
+                .map(|w| w.try_wait_rsp().expect("member rsp is ready"))
+                .collect()),
+            Err(WaitError::Timeout) => {
+                let missing: Vec<_> = (0..self.members.len())
+                    .filter(|i| !self.members[*i].is_ready())
+                    .collect();
+                let msg = format!("wait all timeout, missing members: {missing:?}");
+                Err(io::Error::new(io::ErrorKind::TimedOut, msg))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+
+    #[test]
+    fn test_wait_all() {
+        let mut group = WaiterGroup::<usize>::new();
+        for i in 0..3 {
+            let setter = group.add();
+            go!(move || setter.set_rsp(i * 100));
+        }
+        assert_eq!(group.len(), 3);
+
+        let rsps = group.wait_all(None).unwrap();
+        assert_eq!(rsps, vec![0, 100, 200]);
+    }
+
+    #[test]
+    fn test_wait_all_timeout() {
+        let mut group = WaiterGroup::<usize>::new();
+        let setters: Vec<_> = (0..3).map(|_| group.add()).collect();
+        setters[0].set_rsp(0);
+        setters[2].set_rsp(200);
+
+        let err = group.wait_all(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("[1]"));
+
+        // the received rsps are kept
+        setters[1].set_rsp(100);
+        let rsps = group.wait_all(None).unwrap();
+        assert_eq!(rsps, vec![0, 100, 200]);
+    }
+
+    #[test]
+    fn test_wait_all_canceled_panics() {
+        let mut group = WaiterGroup::<usize>::new();
+        let _setters: Vec<_> = (0..2).map(|_| group.add()).collect();
+        let group = Arc::new(group);
+
+        let group_1 = group.clone();
+        let h = go!(move || group_1.wait_all(None));
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound after the blocker is unregistered
+        assert!(h.join().is_err());
+        for waiter in group.members.iter() {
+            assert_eq!(waiter.observers(), 0);
+        }
+    }
+}
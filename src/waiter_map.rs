@@ -1,26 +1,58 @@
+use may::coroutine::{self, ParkError};
+use may::sync::Blocker;
+use scc::hash_map::Entry;
 use scc::HashMap;
 
-use crate::waiter::Waiter;
+use crate::waiter::{WaitError, Waiter};
+use crate::WaiterStats;
 
-use std::hash::Hash;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
 use std::io;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
-pub struct MapWaiterOwned<K: Hash + Eq, T> {
-    map: Arc<WaiterMap<K, T>>,
+/// Owned waiter guard to wait the response
+///
+/// the clones share the same entry, it's removed when the last one dropped.
+/// the count of the clones is kept in the map entry. only one clone may
+/// wait at a time, the others could set or take the rsp
+pub struct MapWaiterOwned<K: Hash + Eq, T, S: BuildHasher = RandomState, M = ()> {
+    map: Arc<WaiterMap<K, T, S, M>>,
     id: K,
 }
 
-impl<K: Hash + Eq, T> MapWaiterOwned<K, T> {
+impl<K: Hash + Eq, T, S: BuildHasher, M> MapWaiterOwned<K, T, S, M> {
     /// wait for response
     pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
         self.map.wait_rsp(&self.id, timeout.into())
     }
 
+    /// wait for response without timeout
+    pub fn wait_rsp_blocking(&self) -> io::Result<T> {
+        self.wait_rsp(None)
+    }
+
+    /// wait for response for at most `timeout`
+    pub fn wait_rsp_timeout(&self, timeout: Duration) -> io::Result<T> {
+        self.wait_rsp(timeout)
+    }
+
+    /// wait for response until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> io::Result<T> {
+        self.map.wait_rsp_until(&self.id, deadline)
+    }
+
     /// set rsp for the waiter
     pub fn set_rsp(&self, rsp: T) -> Result<(), T> {
-        self.map.set_rsp(&self.id, rsp)
+        self.map.set_rsp(&self.id, rsp).map(drop)
+    }
+
+    /// take the rsp that arrived after the wait returned, e.g. after a timeout
+    pub fn take_rsp(&self) -> Option<T> {
+        self.map.take_rsp(&self.id)
     }
 
     /// get id
@@ -29,164 +61,1149 @@ impl<K: Hash + Eq, T> MapWaiterOwned<K, T> {
     }
 }
 
-impl<K: Hash + Eq, T> Drop for MapWaiterOwned<K, T> {
+impl<K: Hash + Eq, T, S: BuildHasher, M> MapWaiterOwned<K, T, S, M> {
+    /// split the guard into the map and the id, the entry is kept in the map
+    ///
+    /// the other clones of the guard would still remove the entry when dropped
+    pub fn into_parts(self) -> (Arc<WaiterMap<K, T, S, M>>, K) {
+        let this = std::mem::ManuallyDrop::new(self);
+        this.map.map.read(&this.id, |_k, entry| {
+            entry.refs.fetch_sub(1, Ordering::AcqRel);
+        });
+        // the fields are moved out only once and the drop is skipped
+        unsafe { (std::ptr::read(&this.map), std::ptr::read(&this.id)) }
+    }
+
+    /// rebuild the guard from the parts returned by `into_parts`
+    ///
+    /// the guard joins the clones that are still alive, the entry would be
+    /// removed when the last of them dropped
+    pub fn from_parts(map: Arc<WaiterMap<K, T, S, M>>, id: K) -> Self {
+        map.map.read(&id, |_k, entry| {
+            entry.refs.fetch_add(1, Ordering::Relaxed);
+        });
+        MapWaiterOwned { map, id }
+    }
+}
+
+impl<K: Hash + Eq + Clone, T, S: BuildHasher, M> Clone for MapWaiterOwned<K, T, S, M> {
+    fn clone(&self) -> Self {
+        MapWaiterOwned::from_parts(self.map.clone(), self.id.clone())
+    }
+}
+
+impl<K: Hash + Eq, T, S: BuildHasher, M> Drop for MapWaiterOwned<K, T, S, M> {
     fn drop(&mut self) {
-        // remove the entry
-        self.map.del_waiter(&self.id);
+        // remove the entry when the last clone dropped
+        let last = |entry: &mut MapEntry<T, M>| entry.refs.fetch_sub(1, Ordering::AcqRel) == 1;
+        if let Some((_id, entry)) = self.map.map.remove_if(&self.id, last) {
+            self.map.removed(&entry);
+        }
+    }
+}
+
+/// Waiter guard that doesn't keep the map alive
+///
+/// the map is upgraded for each call, an error is returned if it's dropped
+pub struct MapWaiterWeak<K: Hash + Eq, T, S: BuildHasher = RandomState, M = ()> {
+    map: Weak<WaiterMap<K, T, S, M>>,
+    id: K,
+}
+
+impl<K: Hash + Eq, T, S: BuildHasher, M> MapWaiterWeak<K, T, S, M> {
+    /// wait for response
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
+        let map = self.map.upgrade().ok_or_else(map_dropped)?;
+        map.wait_rsp(&self.id, timeout.into())
+    }
+
+    /// wait for response without timeout
+    pub fn wait_rsp_blocking(&self) -> io::Result<T> {
+        self.wait_rsp(None)
+    }
+
+    /// wait for response for at most `timeout`
+    pub fn wait_rsp_timeout(&self, timeout: Duration) -> io::Result<T> {
+        self.wait_rsp(timeout)
+    }
+
+    /// wait for response until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> io::Result<T> {
+        let map = self.map.upgrade().ok_or_else(map_dropped)?;
+        map.wait_rsp_until(&self.id, deadline)
+    }
+
+    /// set rsp for the waiter
+    /// return the rsp back if the map is dropped
+    pub fn set_rsp(&self, rsp: T) -> Result<(), T> {
+        match self.map.upgrade() {
+            Some(map) => map.set_rsp(&self.id, rsp).map(drop),
+            None => Err(rsp),
+        }
+    }
+
+    /// take the rsp that arrived after the wait returned, e.g. after a timeout
+    pub fn take_rsp(&self) -> Option<T> {
+        self.map.upgrade()?.take_rsp(&self.id)
+    }
+
+    /// check if the map is dropped
+    pub fn is_map_dropped(&self) -> bool {
+        self.map.strong_count() == 0
+    }
+
+    /// get id
+    pub fn id(&self) -> &K {
+        &self.id
+    }
+}
+
+impl<K: Hash + Eq, T, S: BuildHasher, M> Drop for MapWaiterWeak<K, T, S, M> {
+    fn drop(&mut self) {
+        // remove the entry if the map is still alive
+        if let Some(map) = self.map.upgrade() {
+            map.del_waiter(&self.id);
+        }
     }
 }
 
 /// Water guard to wait the response
 #[derive(Debug)]
-pub struct MapWaiter<'a, K: Hash + Eq + 'a, T: 'a> {
-    owner: &'a WaiterMap<K, T>,
+pub struct MapWaiter<'a, K: Hash + Eq + 'a, T: 'a, S: BuildHasher = RandomState, M = ()> {
+    owner: &'a WaiterMap<K, T, S, M>,
     id: K,
+    // don't remove the entry when dropped
+    persistent: bool,
 }
 
-impl<K: Hash + Eq, T> MapWaiter<'_, K, T> {
+impl<K: Hash + Eq, T, S: BuildHasher, M> MapWaiter<'_, K, T, S, M> {
     /// wait for response
     pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
         self.owner.wait_rsp(&self.id, timeout.into())
     }
+
+    /// wait for response without timeout
+    pub fn wait_rsp_blocking(&self) -> io::Result<T> {
+        self.wait_rsp(None)
+    }
+
+    /// wait for response for at most `timeout`
+    pub fn wait_rsp_timeout(&self, timeout: Duration) -> io::Result<T> {
+        self.wait_rsp(timeout)
+    }
+
+    /// wait for response until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> io::Result<T> {
+        self.owner.wait_rsp_until(&self.id, deadline)
+    }
+
+    /// set rsp for the waiter
+    pub fn set_rsp(&self, rsp: T) -> Result<(), T> {
+        self.owner.set_rsp(&self.id, rsp).map(drop)
+    }
+
+    /// take the rsp that arrived after the wait returned, e.g. after a timeout
+    pub fn take_rsp(&self) -> Option<T> {
+        self.owner.take_rsp(&self.id)
+    }
+
+    /// get id
+    pub fn id(&self) -> &K {
+        &self.id
+    }
 }
 
-impl<K: Hash + Eq, T> Drop for MapWaiter<'_, K, T> {
+impl<K: Hash + Eq, T, S: BuildHasher, M> Drop for MapWaiter<'_, K, T, S, M> {
     fn drop(&mut self) {
+        if self.persistent {
+            return;
+        }
         // remove the entry
         self.owner.del_waiter(&self.id);
     }
 }
 
+/// Waiter guard that looks up the entry with a borrowed key
+///
+/// the map owns the key, so the key type doesn't need to be `Clone`,
+/// the guard borrows both the map and the lookup key
+#[derive(Debug)]
+pub struct MapWaiterRef<'a, 'k, K, Q, T, S = RandomState, M = ()>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher,
+{
+    owner: &'a WaiterMap<K, T, S, M>,
+    id: &'k Q,
+}
+
+impl<K, Q, T, S, M> MapWaiterRef<'_, '_, K, Q, T, S, M>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher,
+{
+    /// wait for response
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
+        self.owner.wait_rsp(self.id, timeout.into())
+    }
+
+    /// wait for response without timeout
+    pub fn wait_rsp_blocking(&self) -> io::Result<T> {
+        self.wait_rsp(None)
+    }
+
+    /// wait for response for at most `timeout`
+    pub fn wait_rsp_timeout(&self, timeout: Duration) -> io::Result<T> {
+        self.wait_rsp(timeout)
+    }
+
+    /// wait for response until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> io::Result<T> {
+        self.owner.wait_rsp_until(self.id, deadline)
+    }
+
+    /// set rsp for the waiter
+    pub fn set_rsp(&self, rsp: T) -> Result<(), T> {
+        self.owner.set_rsp(self.id, rsp).map(drop)
+    }
+
+    /// take the rsp that arrived after the wait returned, e.g. after a timeout
+    pub fn take_rsp(&self) -> Option<T> {
+        self.owner.take_rsp(self.id)
+    }
+
+    /// get id
+    pub fn id(&self) -> &Q {
+        self.id
+    }
+}
+
+impl<K, Q, T, S, M> Drop for MapWaiterRef<'_, '_, K, Q, T, S, M>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        // remove the entry
+        self.owner.del_waiter(self.id);
+    }
+}
+
+// the map value, the map bookkeeping is kept here instead of in the waiter
+struct MapEntry<T, M> {
+    waiter: Arc<Waiter<T, M>>,
+    // the registration order in the map
+    seq: u64,
+    // holds a rsp deposited for a key that is not registered yet
+    unclaimed: AtomicBool,
+    // the unclaimed rsp is counted by the map `buffered`
+    buffered: AtomicBool,
+    // the number of the live `MapWaiterOwned` clones
+    refs: AtomicUsize,
+}
+
+impl<T, M> MapEntry<T, M> {
+    // mark or unmark the entry as buffered, return the previous mark
+    fn set_buffered(&self, buffered: bool) -> bool {
+        self.buffered.swap(buffered, Ordering::AcqRel)
+    }
+}
+
 /// Waiter map that could be used to wait response for given keys
-pub struct WaiterMap<K, T> {
-    map: HashMap<K, Box<Waiter<T>>>,
+pub struct WaiterMap<K, T, S: BuildHasher = RandomState, M = ()> {
+    map: HashMap<K, MapEntry<T, M>, S>,
+    // the registration order of the next waiter
+    seq: AtomicU64,
+    // the next key minted by `new_waiter_auto`
+    next_key: AtomicU64,
+    // the number of rsps stored by `set_rsp_or_store` and not yet registered
+    buffered: AtomicUsize,
 }
 
-impl<K: Hash + Eq, T> std::fmt::Debug for WaiterMap<K, T> {
+impl<K: Hash + Eq, T, S: BuildHasher, M> std::fmt::Debug for WaiterMap<K, T, S, M> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "WaiterMap{{ ... }}")
     }
 }
 
-impl<K: Hash + Eq, T> Default for WaiterMap<K, T> {
+impl<K: Hash + Eq, T, M> Default for WaiterMap<K, T, RandomState, M> {
     fn default() -> Self {
         WaiterMap::new()
     }
 }
 
-impl<K: Hash + Eq, T> WaiterMap<K, T> {
+impl<K: Hash + Eq, T, M> WaiterMap<K, T, RandomState, M> {
     pub fn new() -> Self {
         WaiterMap {
             map: HashMap::new(),
+            seq: AtomicU64::new(0),
+            next_key: AtomicU64::new(0),
+            buffered: AtomicUsize::new(0),
+        }
+    }
+
+    /// create a map that could hold `cap` waiters without resizing
+    pub fn with_capacity(cap: usize) -> Self {
+        WaiterMap {
+            map: HashMap::with_capacity(cap),
+            seq: AtomicU64::new(0),
+            next_key: AtomicU64::new(0),
+            buffered: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<K: Hash + Eq, T, S: BuildHasher, M> WaiterMap<K, T, S, M> {
+    /// create a map that hashes the keys with the given hasher
+    pub fn with_hasher(hasher: S) -> Self {
+        WaiterMap {
+            map: HashMap::with_hasher(hasher),
+            seq: AtomicU64::new(0),
+            next_key: AtomicU64::new(0),
+            buffered: AtomicUsize::new(0),
+        }
+    }
+
+    /// create a map with the given capacity and hasher
+    pub fn with_capacity_and_hasher(cap: usize, hasher: S) -> Self {
+        WaiterMap {
+            map: HashMap::with_capacity_and_hasher(cap, hasher),
+            seq: AtomicU64::new(0),
+            next_key: AtomicU64::new(0),
+            buffered: AtomicUsize::new(0),
+        }
+    }
+
+    // create an entry that records the registration order
+    fn make_entry(&self, meta: M) -> MapEntry<T, M> {
+        MapEntry {
+            waiter: Arc::new(Waiter::with_meta(meta)),
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            unclaimed: AtomicBool::new(false),
+            buffered: AtomicBool::new(false),
+            refs: AtomicUsize::new(0),
+        }
+    }
+
+    // create an unclaimed entry that holds the rsp for a key not registered yet
+    fn deposit_entry(&self, rsp: T) -> MapEntry<T, M>
+    where
+        M: Default,
+    {
+        let entry = self.make_entry(M::default());
+        entry.waiter.set_rsp(rsp);
+        entry.unclaimed.store(true, Ordering::Release);
+        entry
+    }
+
+    // insert a new entry for the key, or take over the unclaimed entry of a
+    // deposited rsp, return the key back if it's already registered
+    fn insert_entry(&self, id: K, meta: M) -> Result<(), K> {
+        let (mut id, mut new_entry) = (id, self.make_entry(meta));
+        loop {
+            (id, new_entry) = match self.map.insert(id, new_entry) {
+                Ok(()) => return Ok(()),
+                Err(v) => v,
+            };
+            let Some(mut entry) = self.map.get(&id) else {
+                // the entry is removed in between, try again
+                continue;
+            };
+            if !entry.get().unclaimed.swap(false, Ordering::AcqRel) {
+                return Err(id);
+            }
+            // move the deposited rsp to the new waiter that carries the meta
+            if let Some(rsp) = entry.get().waiter.take_rsp() {
+                new_entry.waiter.set_rsp(rsp);
+            }
+            let old = entry.insert(new_entry);
+            drop(entry);
+            self.removed(&old);
+            return Ok(());
         }
     }
 
+    /// the number of waiters the map could hold without resizing
+    pub fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+
     /// return a waiter on the stack!
-    pub fn new_waiter(&self, id: K) -> MapWaiter<K, T>
+    /// panic if the key already exists in the map
+    pub fn new_waiter(&self, id: K) -> MapWaiter<'_, K, T, S, M>
     where
         K: Clone,
+        M: Default,
     {
-        // if we add a same key, the old waiter would be lost!
-        if self
-            .map
-            .insert(id.clone(), Box::new(Waiter::new()))
-            .is_err()
-        {
-            panic!("key already exists in the map!")
-        };
-        MapWaiter { owner: self, id }
+        self.new_waiter_with_meta(id, M::default())
+    }
+
+    /// return a waiter that carries the metadata
+    /// panic if the key already exists in the map
+    pub fn new_waiter_with_meta(&self, id: K, meta: M) -> MapWaiter<'_, K, T, S, M>
+    where
+        K: Clone,
+    {
+        self.try_new_waiter_with_meta(id, meta)
+            .unwrap_or_else(|_| panic!("key already exists in the map!"))
     }
 
     /// return a waiter on the stack!
-    pub fn new_waiter_owned(self: &Arc<Self>, id: K) -> MapWaiterOwned<K, T>
+    /// return the key back if it already exists in the map
+    ///
+    /// a rsp deposited by `deposit_rsp` or `set_rsp_or_store` before the
+    /// registration is taken over by the new waiter
+    pub fn try_new_waiter(&self, id: K) -> Result<MapWaiter<'_, K, T, S, M>, K>
+    where
+        K: Clone,
+        M: Default,
+    {
+        self.try_new_waiter_with_meta(id, M::default())
+    }
+
+    /// return a waiter that carries the metadata
+    /// return the key back if it already exists in the map
+    pub fn try_new_waiter_with_meta(&self, id: K, meta: M) -> Result<MapWaiter<'_, K, T, S, M>, K>
+    where
+        K: Clone,
+    {
+        self.insert_entry(id.clone(), meta)?;
+        Ok(MapWaiter {
+            owner: self,
+            id,
+            persistent: false,
+        })
+    }
+
+    /// return a waiter that already holds the rsp, the wait would return at once
+    /// panic if the key already exists in the map
+    pub fn register_and_set(&self, id: K, rsp: T) -> MapWaiter<'_, K, T, S, M>
+    where
+        K: Clone,
+        M: Default,
+    {
+        let entry = self.make_entry(M::default());
+        entry.waiter.set_rsp(rsp);
+        if self.map.insert(id.clone(), entry).is_err() {
+            panic!("key already exists in the map!");
+        }
+        MapWaiter {
+            owner: self,
+            id,
+            persistent: false,
+        }
+    }
+
+    /// return a waiter that looks up the entry with `lookup`, which must
+    /// be equal to `id`, so that the key is moved into the map without clone
+    /// panic if the key already exists in the map or `lookup` doesn't match `id`
+    pub fn new_waiter_by_ref<'k, Q>(
+        &self,
+        id: K,
+        lookup: &'k Q,
+    ) -> MapWaiterRef<'_, 'k, K, Q, T, S, M>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        M: Default,
+    {
+        self.try_new_waiter_by_ref(id, lookup)
+            .unwrap_or_else(|_| panic!("key already exists in the map!"))
+    }
+
+    /// return a waiter that looks up the entry with `lookup`
+    /// return the key back if it already exists in the map
+    /// panic if `lookup` doesn't match `id`
+    pub fn try_new_waiter_by_ref<'k, Q>(
+        &self,
+        id: K,
+        lookup: &'k Q,
+    ) -> Result<MapWaiterRef<'_, 'k, K, Q, T, S, M>, K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        M: Default,
+    {
+        assert!(id.borrow() == lookup, "lookup key doesn't match the id");
+        self.insert_entry(id, M::default())?;
+        Ok(MapWaiterRef {
+            owner: self,
+            id: lookup,
+        })
+    }
+
+    /// return an owned waiter that only holds a weak reference to the map
+    /// panic if the key already exists in the map
+    pub fn new_waiter_weak(self: &Arc<Self>, id: K) -> MapWaiterWeak<K, T, S, M>
+    where
+        K: Clone,
+        M: Default,
+    {
+        self.try_new_waiter_weak(id)
+            .unwrap_or_else(|_| panic!("key already exists in the map!"))
+    }
+
+    /// return an owned waiter that only holds a weak reference to the map
+    /// return the key back if it already exists in the map
+    pub fn try_new_waiter_weak(self: &Arc<Self>, id: K) -> Result<MapWaiterWeak<K, T, S, M>, K>
     where
         K: Clone,
+        M: Default,
     {
-        // if we add a same key, the old waiter would be lost!
         if self
             .map
-            .insert(id.clone(), Box::new(Waiter::new()))
+            .insert(id.clone(), self.make_entry(M::default()))
             .is_err()
         {
-            panic!("key already exists in the map!")
+            return Err(id);
         };
-        MapWaiterOwned {
-            map: self.clone(),
+        Ok(MapWaiterWeak {
+            map: Arc::downgrade(self),
             id,
-        }
+        })
     }
 
-    // used internally
-    fn del_waiter(&self, id: &K) -> Option<(K, Box<Waiter<T>>)> {
-        self.map.remove(id)
+    /// return a waiter on the stack!
+    /// reuse the existing waiter if the key already exists in the map
+    /// note that the entry is removed when any of the returned guards dropped
+    pub fn get_or_create_waiter(&self, id: K) -> MapWaiter<'_, K, T, S, M>
+    where
+        K: Clone,
+        M: Default,
+    {
+        let entry = self
+            .map
+            .entry(id.clone())
+            .or_insert_with(|| self.make_entry(M::default()));
+        self.claim(entry.get());
+        drop(entry);
+        MapWaiter {
+            owner: self,
+            id,
+            persistent: false,
+        }
     }
 
-    fn wait_rsp(&self, id: &K, timeout: Option<Duration>) -> io::Result<T> {
-        fn extend_lifetime<'a, T>(r: &T) -> &'a T {
-            unsafe { ::std::mem::transmute(r) }
+    /// return a waiter that keeps the entry in the map when dropped
+    /// reuse the existing waiter if the key already exists in the map
+    ///
+    /// the waiter could be waited again for the next rsp, and the entry must
+    /// be removed by `remove_waiter` explicitly
+    pub fn new_persistent_waiter(&self, id: K) -> MapWaiter<'_, K, T, S, M>
+    where
+        K: Clone,
+        M: Default,
+    {
+        let entry = self
+            .map
+            .entry(id.clone())
+            .or_insert_with(|| self.make_entry(M::default()));
+        self.claim(entry.get());
+        drop(entry);
+        MapWaiter {
+            owner: self,
+            id,
+            persistent: true,
         }
+    }
 
-        let waiter = match self.map.get(id) {
-            // extends the lifetime of the waiter ref
-            Some(v) => extend_lifetime(v.as_ref()),
-            None => unreachable!("can't find id in waiter map!"),
-        };
+    /// remove the waiter for the key, return false if there is no such waiter
+    /// the wait on the removed waiter would return NotFound error
+    pub fn remove_waiter<Q>(&self, id: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.del_waiter(id).is_some()
+    }
 
-        waiter.wait_rsp(timeout)
+    /// remove the waiter for the key, return the rsp it holds but not yet taken
+    ///
+    /// unlike `remove_waiter` the rsp that races in is not discarded
+    pub fn remove_and_take<Q>(&self, id: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (_id, waiter) = self.del_waiter(id)?;
+        waiter.take_rsp()
     }
 
-    /// set rsp for the corresponding waiter
-    pub fn set_rsp(&self, id: &K, rsp: T) -> Result<(), T> {
-        match self.map.get(id) {
-            Some(waiter) => {
-                waiter.set_rsp(rsp);
-                Ok(())
-            }
-            None => Err(rsp),
-        }
+    /// return a waiter on the stack!
+    /// panic if the key already exists in the map
+    pub fn new_waiter_owned(self: &Arc<Self>, id: K) -> MapWaiterOwned<K, T, S, M>
+    where
+        K: Clone,
+        M: Default,
+    {
+        self.new_waiter_owned_with_meta(id, M::default())
     }
 
-    /// cancel all the waiting waiter, all wait would return NotFound error
-    pub fn cancel_all(&self) {
-        self.map.scan(|_k, waiter| {
-            waiter.cancel_wait();
-        });
+    /// return an owned waiter that carries the metadata
+    /// panic if the key already exists in the map
+    pub fn new_waiter_owned_with_meta(
+        self: &Arc<Self>,
+        id: K,
+        meta: M,
+    ) -> MapWaiterOwned<K, T, S, M>
+    where
+        K: Clone,
+    {
+        self.try_new_waiter_owned_with_meta(id, meta)
+            .unwrap_or_else(|_| panic!("key already exists in the map!"))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use may::go;
+    /// return a waiter on the stack!
+    /// return the key back if it already exists in the map
+    pub fn try_new_waiter_owned(self: &Arc<Self>, id: K) -> Result<MapWaiterOwned<K, T, S, M>, K>
+    where
+        K: Clone,
+        M: Default,
+    {
+        self.try_new_waiter_owned_with_meta(id, M::default())
+    }
 
-    #[test]
-    fn test_waiter_map() {
-        use std::sync::Arc;
-        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
-        let req_map_1 = req_map.clone();
+    /// return an owned waiter that carries the metadata
+    /// return the key back if it already exists in the map
+    pub fn try_new_waiter_owned_with_meta(
+        self: &Arc<Self>,
+        id: K,
+        meta: M,
+    ) -> Result<MapWaiterOwned<K, T, S, M>, K>
+    where
+        K: Clone,
+    {
+        self.insert_entry(id.clone(), meta)?;
+        Ok(MapWaiterOwned::from_parts(self.clone(), id))
+    }
 
-        let key = 1234;
+    // used internally
+    fn del_waiter<Q>(&self, id: &Q) -> Option<(K, Arc<Waiter<T, M>>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (k, entry) = self.map.remove(id)?;
+        self.removed(&entry);
+        Some((k, entry.waiter))
+    }
 
-        // one coroutine wait data send from another coroutine
-        // prepare the waiter first
-        let waiter = req_map.new_waiter(key);
+    // the entry is removed from the map
+    fn removed(&self, entry: &MapEntry<T, M>) {
+        self.unbuffer(entry);
+        // wake up the wait on the removed waiter
+        entry.waiter.cancel_wait();
+    }
 
-        // trigger the rsp in another coroutine
-        go!(move || req_map_1.set_rsp(&key, 100).ok());
+    // the deposited rsp is claimed by a registration
+    fn claim(&self, entry: &MapEntry<T, M>) {
+        entry.unclaimed.store(false, Ordering::Release);
+        self.unbuffer(entry);
+    }
 
-        // this will block until the rsp was set
-        let result = waiter.wait_rsp(None).unwrap();
-        assert_eq!(result, 100);
+    // the buffered rsp is claimed by a registration or removed
+    fn unbuffer(&self, entry: &MapEntry<T, M>) {
+        if entry.set_buffered(false) {
+            self.buffered.fetch_sub(1, Ordering::AcqRel);
+        }
     }
 
-    #[test]
-    fn test_map_waiter() {
-        use std::sync::Arc;
-        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
-        let key = 1234;
+    // get the waiter ref without holding the entry lock
+    fn waiter<Q>(&self, id: &Q) -> io::Result<Arc<Waiter<T, M>>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.map.read(id, |_k, entry| entry.waiter.clone()) {
+            // hold the waiter, so that it's still valid if removed while waiting
+            Some(waiter) => Ok(waiter),
+            // the entry could be removed by others
+            None => Err(not_found()),
+        }
+    }
 
-        // one coroutine wait data send from another coroutine
-        // prepare the waiter first
+    // the wait is canceled because the entry is removed
+    fn wait_result<Q>(
+        &self,
+        id: &Q,
+        waiter: &Arc<Waiter<T, M>>,
+        ret: Result<T, WaitError>,
+    ) -> io::Result<T>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match ret {
+            Err(WaitError::Canceled) => {
+                let removed = !self
+                    .map
+                    .read(id, |_k, entry| Arc::ptr_eq(&entry.waiter, waiter))
+                    .unwrap_or(false);
+                if removed {
+                    return Err(not_found());
+                }
+                Err(WaitError::Canceled.into())
+            }
+            ret => ret.map_err(io::Error::from),
+        }
+    }
+
+    fn wait_rsp<Q>(&self, id: &Q, timeout: Option<Duration>) -> io::Result<T>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let waiter = self.waiter(id)?;
+        // the coroutine cancel unwinds like `Waiter::wait_rsp`
+        let ret = waiter.wait_rsp_boxed(timeout, true).map(|rsp| *rsp);
+        self.wait_result(id, &waiter, ret)
+    }
+
+    fn wait_rsp_until<Q>(&self, id: &Q, deadline: Instant) -> io::Result<T>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = Instant::now();
+        if deadline <= now {
+            // still report the removed entry
+            self.waiter(id)?;
+            return Err(WaitError::Timeout.into());
+        }
+        self.wait_rsp(id, Some(deadline - now))
+    }
+
+    /// wait for the rsp of the key, return `default` if it doesn't arrive in time
+    ///
+    /// the `default` is also returned when the wait is canceled or there is no such key
+    pub fn wait_rsp_or<Q, D>(&self, id: &Q, timeout: D, default: T) -> T
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        D: Into<Option<Duration>>,
+    {
+        self.wait_rsp(id, timeout.into()).unwrap_or(default)
+    }
+
+    /// wait for any of the keys to get the rsp, return the key and the rsp
+    ///
+    /// the waiters of the other keys are not touched, they are still in the map
+    /// and would be removed by their guards as usual. the wait returns
+    /// `Interrupted` error once any of the waiters is canceled or removed
+    pub fn wait_any(&self, ids: &[K], timeout: Option<Duration>) -> io::Result<(K, T)>
+    where
+        K: Clone,
+    {
+        let waiters = ids
+            .iter()
+            .map(|id| self.waiter(id))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // register one blocker on all the waiters, so that any rsp would wake us up
+        // the coroutine cancel is returned by the park so that we could unregister
+        let blocker = Arc::new(Blocker::new(true));
+        for waiter in waiters.iter() {
+            waiter.add_observer(&blocker);
+        }
+
+        let deadline = timeout.map(|d| Instant::now() + d);
+        let mut cancel_panic = false;
+        let ret = loop {
+            let rsp = waiters
+                .iter()
+                .enumerate()
+                .find_map(|(i, w)| w.try_wait_rsp().map(|rsp| (ids[i].clone(), rsp)));
+            if let Some(rsp) = rsp {
+                break Ok(rsp);
+            }
+            // one of the waiters is canceled or removed from the map
+            if waiters.iter().any(|w| w.is_canceled()) {
+                break Err(WaitError::Canceled.into());
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        break Err(WaitError::Timeout.into());
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+
+            match blocker.park(timeout) {
+                Ok(_) => {}
+                Err(ParkError::Timeout) => break Err(WaitError::Timeout.into()),
+                Err(ParkError::Canceled) => {
+                    cancel_panic = true;
+                    break Err(WaitError::Canceled.into());
+                }
+            }
+        };
+
+        for waiter in waiters.iter() {
+            waiter.remove_observer(&blocker);
+        }
+        // the coroutine cancel unwinds like `Waiter::wait_rsp`
+        if cancel_panic {
+            coroutine::trigger_cancel_panic();
+        }
+        ret
+    }
+
+    fn take_rsp<Q>(&self, id: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map
+            .read(id, |_, entry| entry.waiter.take_rsp())
+            .flatten()
+    }
+
+    /// call `f` with the metadata of the waiter, return `None` if there is no such waiter
+    pub fn read_meta<Q, R, F>(&self, id: &Q, f: F) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(&M) -> R,
+    {
+        self.map.read(id, |_k, entry| f(entry.waiter.meta()))
+    }
+
+    /// set rsp for the corresponding waiter
+    ///
+    /// return the previous rsp if the waiter still holds an unconsumed one,
+    /// e.g. a duplicated reply, or the rsp back if there is no such waiter
+    pub fn set_rsp<Q>(&self, id: &Q, rsp: T) -> Result<Option<T>, T>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.map.get(id) {
+            Some(entry) => Ok(entry.waiter.set_rsp(rsp)),
+            None => Err(rsp),
+        }
+    }
+
+    /// set rsp for the corresponding waiter, return false if there is no such waiter
+    /// the rsp is dropped if not delivered
+    pub fn try_set_rsp<Q>(&self, id: &Q, rsp: T) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.set_rsp(id, rsp).is_ok()
+    }
+
+    /// set rsp for the waiter, create a waiter holding the rsp if there is none
+    ///
+    /// this is for the rsp that may arrive before the waiter registered, the
+    /// waiter registered later by `new_waiter` or `get_or_create_waiter` gets
+    /// the rsp. return the previous rsp if it's not consumed yet
+    pub fn deposit_rsp(&self, id: K, rsp: T) -> Option<T>
+    where
+        M: Default,
+    {
+        match self.map.entry(id) {
+            Entry::Occupied(entry) => entry.get().waiter.set_rsp(rsp),
+            Entry::Vacant(entry) => {
+                entry.insert_entry(self.deposit_entry(rsp));
+                None
+            }
+        }
+    }
+
+    /// set rsp for the waiter, store it for the key that is not registered yet
+    ///
+    /// like `deposit_rsp`, but at most `max_buffered` rsps are stored for the
+    /// keys that are not registered, the rsp is returned back beyond that. the
+    /// stored rsp is counted until its key is registered by `new_waiter`,
+    /// `get_or_create_waiter` or `new_persistent_waiter`, or the entry is removed
+    pub fn set_rsp_or_store(&self, id: K, rsp: T, max_buffered: usize) -> Result<(), T>
+    where
+        M: Default,
+    {
+        match self.map.entry(id) {
+            Entry::Occupied(entry) => {
+                entry.get().waiter.set_rsp(rsp);
+            }
+            Entry::Vacant(entry) => {
+                let full = self
+                    .buffered
+                    .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |n| {
+                        (n < max_buffered).then_some(n + 1)
+                    })
+                    .is_err();
+                if full {
+                    return Err(rsp);
+                }
+                let new_entry = self.deposit_entry(rsp);
+                new_entry.set_buffered(true);
+                entry.insert_entry(new_entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// the number of rsps stored by `set_rsp_or_store` for the unregistered keys
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Acquire)
+    }
+
+    /// set rsp for each key in the items
+    /// return the items that have no corresponding waiter
+    pub fn set_rsp_bulk<I: IntoIterator<Item = (K, T)>>(&self, items: I) -> Vec<(K, T)> {
+        items
+            .into_iter()
+            .filter_map(|(id, rsp)| self.set_rsp(&id, rsp).err().map(|rsp| (id, rsp)))
+            .collect()
+    }
+
+    /// the number of waiters in the map, this would scan the whole map
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// return true if there is no waiter in the map
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// return true if there is a waiter for the key
+    pub fn contains_key<Q>(&self, id: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.contains(id)
+    }
+
+    /// cancel the waiter for the key, the wait would return Interrupted error
+    /// return false if there is no such waiter
+    pub fn cancel_one<Q>(&self, id: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map
+            .read(id, |_k, entry| entry.waiter.cancel_wait())
+            .is_some()
+    }
+
+    /// only keep the waiters that the predicate returns true
+    ///
+    /// this is used to prune the stale entries, the wait on the removed waiters
+    /// would return NotFound error
+    pub fn retain<F: Fn(&K, &Waiter<T, M>) -> bool>(&self, f: F) {
+        self.map.retain(|k, entry| {
+            let keep = f(k, &entry.waiter);
+            if !keep {
+                self.removed(entry);
+            }
+            keep
+        });
+    }
+
+    /// remove the waiters that the rsp was set but not consumed, return the keys and rsps
+    pub fn take_ready(&self) -> Vec<(K, T)>
+    where
+        K: Clone,
+    {
+        let mut ready = Vec::new();
+        self.map.prune(|k, entry| match entry.waiter.take_rsp() {
+            Some(rsp) => {
+                ready.push((k.clone(), rsp));
+                // the wait on the removed waiter would return NotFound error
+                self.removed(&entry);
+                None
+            }
+            None => Some(entry),
+        });
+        ready
+    }
+
+    /// remove the waiters that the rsp was set but never consumed
+    pub fn prune_ready_but_unwaited(&self) {
+        self.retain(|_k, waiter| !waiter.is_ready());
+    }
+
+    /// call the closure for each waiter in the map
+    pub fn for_each<F: FnMut(&K, &Waiter<T, M>)>(&self, mut f: F) {
+        self.map.scan(|k, entry| f(k, &entry.waiter));
+    }
+
+    /// count the waiters in the map, this would scan the whole map
+    pub fn stats(&self) -> WaiterStats {
+        let mut stats = WaiterStats::default();
+        self.map.scan(|_k, entry| stats.add(&entry.waiter));
+        stats
+    }
+
+    /// call the closure for each waiter in the map until it returns an error
+    /// return the first error
+    pub fn try_for_each<E, F>(&self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(&K, &Waiter<T, M>) -> Result<(), E>,
+    {
+        let mut ret = Ok(());
+        self.map.any(|k, entry| match f(k, &entry.waiter) {
+            Ok(()) => false,
+            Err(e) => {
+                ret = Err(e);
+                true
+            }
+        });
+        ret
+    }
+
+    /// remove and return all the entries in the map
+    ///
+    /// the guards of the drained entries could still be dropped safely, but
+    /// they can't wait on the drained waiter anymore
+    pub fn drain(&self) -> Vec<(K, Arc<Waiter<T, M>>)> {
+        let mut entries = Vec::new();
+        while let Some(entry) = self.map.first_entry() {
+            let (k, entry) = entry.remove_entry();
+            self.unbuffer(&entry);
+            entries.push((k, entry.waiter));
+        }
+        entries
+    }
+
+    /// set the same rsp for all the waiters in the map
+    pub fn set_rsp_all(&self, rsp: T)
+    where
+        T: Clone,
+    {
+        self.map.scan(|_k, entry| {
+            entry.waiter.set_rsp(rsp.clone());
+        });
+    }
+
+    /// set rsp made by `make` for the waiters whose key matches `pred`
+    pub fn set_rsp_where<P, F>(&self, pred: P, make: F)
+    where
+        P: Fn(&K) -> bool,
+        F: Fn(&K) -> T,
+    {
+        self.map.scan(|k, entry| {
+            if pred(k) {
+                entry.waiter.set_rsp(make(k));
+            }
+        });
+    }
+
+    /// cancel all the waiting waiter, all wait would return Interrupted error
+    pub fn cancel_all(&self) {
+        self.map.scan(|_k, entry| {
+            entry.waiter.cancel_wait();
+        });
+    }
+
+    /// clear the left rsp and pending wake up of all the waiters to reuse them
+    ///
+    /// this must only be called when no coroutine is waiting on any of them
+    pub fn reset_all(&self) {
+        self.map.scan(|_k, entry| entry.waiter.reset());
+    }
+
+    /// set the rsp for all the waiters in their registration order
+    pub fn set_rsp_all_ordered(&self, rsp: T)
+    where
+        T: Clone,
+    {
+        for waiter in self.ordered_waiters() {
+            waiter.set_rsp(rsp.clone());
+        }
+    }
+
+    /// cancel all the waiters in their registration order
+    pub fn cancel_all_ordered(&self) {
+        for waiter in self.ordered_waiters() {
+            waiter.cancel_wait();
+        }
+    }
+
+    // snapshot the waiters sorted by the registration order
+    fn ordered_waiters(&self) -> Vec<Arc<Waiter<T, M>>> {
+        let mut entries = Vec::new();
+        self.map
+            .scan(|_k, entry| entries.push((entry.seq, entry.waiter.clone())));
+        entries.sort_unstable_by_key(|(seq, _)| *seq);
+        entries.into_iter().map(|(_, waiter)| waiter).collect()
+    }
+}
+
+impl<T, S: BuildHasher, M: Default> WaiterMap<u64, T, S, M> {
+    /// mint a fresh key and return it with an owned waiter registered for it
+    ///
+    /// the keys that are already in the map are skipped, e.g. after wrapping around
+    pub fn new_waiter_auto(self: &Arc<Self>) -> (u64, MapWaiterOwned<u64, T, S, M>) {
+        loop {
+            let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+            if let Ok(waiter) = self.try_new_waiter_owned(key) {
+                return (key, waiter);
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq, P, S: BuildHasher, M> WaiterMap<K, Arc<P>, S, M> {
+    /// deliver the shared payload to each key, only the `Arc` is cloned
+    /// return the number of keys that get the rsp
+    pub fn set_rsp_arc(&self, ids: &[K], rsp: Arc<P>) -> usize {
+        ids.iter()
+            .filter(|id| self.set_rsp(id, rsp.clone()).is_ok())
+            .count()
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "can't find id in waiter map")
+}
+
+fn map_dropped() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "waiter map is dropped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+
+    #[test]
+    fn test_waiter_map() {
+        use std::sync::Arc;
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let req_map_1 = req_map.clone();
+
+        let key = 1234;
+
+        // one coroutine wait data send from another coroutine
+        // prepare the waiter first
+        let waiter = req_map.new_waiter(key);
+
+        // trigger the rsp in another coroutine
+        go!(move || req_map_1.set_rsp(&key, 100).ok());
+
+        // this will block until the rsp was set
+        let result = waiter.wait_rsp(None).unwrap();
+        assert_eq!(result, 100);
+    }
+
+    #[test]
+    fn test_map_waiter() {
+        use std::sync::Arc;
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let key = 1234;
+
+        // one coroutine wait data send from another coroutine
+        // prepare the waiter first
         let waiter = Arc::new(req_map.new_waiter_owned(key));
         let waiter_1 = waiter.clone();
 
@@ -197,4 +1214,852 @@ mod tests {
         let result = waiter.wait_rsp(None).unwrap();
         assert_eq!(result, 100);
     }
+
+    #[test]
+    fn test_map_waiter_until() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let req_map_1 = req_map.clone();
+        let key = 1234;
+
+        let waiter = req_map.new_waiter(key);
+        // the deadline is already passed
+        let err = waiter.wait_rsp_until(Instant::now()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        go!(move || req_map_1.set_rsp(&key, 100).ok());
+        let deadline = Instant::now() + Duration::from_secs(2);
+        assert_eq!(waiter.wait_rsp_until(deadline).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_try_new_waiter() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let key = 1234;
+
+        let waiter = req_map.try_new_waiter(key).unwrap();
+        // the key already exists
+        assert_eq!(req_map.try_new_waiter(key).err(), Some(key));
+        assert_eq!(req_map.try_new_waiter_owned(key).err(), Some(key));
+
+        // the failed insert must not remove the existing waiter
+        req_map.set_rsp(&key, 100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_wait_removed_entry() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let key = 1234;
+
+        let waiter = req_map.new_waiter(key);
+        // the entry is removed before waiting
+        req_map.del_waiter(&key);
+        let err = waiter.wait_rsp(None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_len() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        assert!(req_map.is_empty());
+
+        let waiters: Vec<_> = (0..4).map(|i| req_map.new_waiter(i)).collect();
+        assert_eq!(req_map.len(), 4);
+
+        // drop the waiters would remove the entries
+        drop(waiters);
+        assert_eq!(req_map.len(), 0);
+        assert!(req_map.is_empty());
+
+        let _waiter = req_map.new_waiter(1);
+        let waiter = req_map.new_waiter(2);
+        drop(waiter);
+        assert_eq!(req_map.len(), 1);
+    }
+
+    #[test]
+    fn test_cancel_one() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let req_map_1 = req_map.clone();
+
+        let waiter_1 = req_map.new_waiter(1);
+        let waiter_2 = req_map.new_waiter(2);
+        assert!(req_map.contains_key(&1));
+        assert!(!req_map.contains_key(&3));
+        assert!(!req_map.cancel_one(&3));
+
+        go!(move || {
+            req_map_1.cancel_one(&1);
+            req_map_1.set_rsp(&2, 200).ok();
+        });
+
+        let err = waiter_1.wait_rsp(None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        // the other waiter is not affected
+        assert_eq!(waiter_2.wait_rsp(None).unwrap(), 200);
+    }
+
+    #[test]
+    fn test_set_rsp_bulk() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiter_1 = req_map.new_waiter(1);
+        let waiter_3 = req_map.new_waiter(3);
+
+        let failed = req_map.set_rsp_bulk(vec![(1, 100), (2, 200), (3, 300), (4, 400)]);
+        assert_eq!(failed, vec![(2, 200), (4, 400)]);
+        assert_eq!(waiter_1.wait_rsp(None).unwrap(), 100);
+        assert_eq!(waiter_3.wait_rsp(None).unwrap(), 300);
+    }
+
+    #[test]
+    fn test_wait_any() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let req_map_1 = req_map.clone();
+
+        let _waiters: Vec<_> = (1..=3).map(|i| req_map.new_waiter(i)).collect();
+
+        // the second key responds first
+        go!(move || {
+            may::coroutine::sleep(Duration::from_millis(10));
+            req_map_1.set_rsp(&2, 200).ok();
+        });
+
+        let ret = req_map.wait_any(&[1, 2, 3], None).unwrap();
+        assert_eq!(ret, (2, 200));
+
+        // no more rsp
+        let err = req_map
+            .wait_any(&[1, 3], Some(Duration::from_millis(10)))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_wait_any_canceled_panics() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let _waiters: Vec<_> = (1..=2).map(|i| req_map.new_waiter(i)).collect();
+
+        let req_map_1 = req_map.clone();
+        let h = go!(move || req_map_1.wait_any(&[1, 2], None));
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound after the blocker is unregistered
+        assert!(h.join().is_err());
+        req_map.for_each(|_k, waiter| assert_eq!(waiter.observers(), 0));
+    }
+
+    #[test]
+    fn test_wait_any_overlapped() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let _waiters: Vec<_> = (1..=3).map(|i| req_map.new_waiter(i)).collect();
+
+        let req_map_1 = req_map.clone();
+        let h1 = go!(move || req_map_1.wait_any(&[1, 2], None).unwrap());
+        let req_map_2 = req_map.clone();
+        let h2 = go!(move || req_map_2.wait_any(&[2, 3], None).unwrap());
+        may::coroutine::sleep(Duration::from_millis(20));
+
+        // the first wait returns and unregisters only itself from the key 2
+        req_map.set_rsp(&1, 100).unwrap();
+        assert_eq!(h1.join().unwrap(), (1, 100));
+        req_map.set_rsp(&2, 200).unwrap();
+        assert_eq!(h2.join().unwrap(), (2, 200));
+    }
+
+    #[test]
+    fn test_wait_any_removed() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let _waiters: Vec<_> = (1..=2).map(|i| req_map.new_waiter(i)).collect();
+
+        let req_map_1 = req_map.clone();
+        let h = go!(move || req_map_1.wait_any(&[1, 2], None));
+        may::coroutine::sleep(Duration::from_millis(20));
+
+        assert!(req_map.remove_waiter(&1));
+        let err = h.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn test_get_or_create_waiter() {
+        let req_map = WaiterMap::<usize, usize>::new();
+
+        // create path
+        let waiter = req_map.get_or_create_waiter(1);
+        assert_eq!(req_map.len(), 1);
+        req_map.set_rsp(&1, 100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        drop(waiter);
+        assert!(req_map.is_empty());
+
+        // the rsp arrives before the waiter is fetched
+        let slot = req_map.get_or_create_waiter(2);
+        req_map.set_rsp(&2, 200).unwrap();
+        // reuse path
+        let waiter = req_map.get_or_create_waiter(2);
+        assert_eq!(req_map.len(), 1);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 200);
+        drop(slot);
+    }
+
+    #[test]
+    fn test_retain() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiters: Vec<_> = (0..4).map(|i| req_map.new_waiter(i)).collect();
+
+        req_map.retain(|k, _waiter| k % 2 == 0);
+        assert_eq!(req_map.len(), 2);
+        assert!(req_map.contains_key(&0));
+        assert!(req_map.contains_key(&2));
+
+        req_map.set_rsp(&2, 200).unwrap();
+        req_map.prune_ready_but_unwaited();
+        assert_eq!(req_map.len(), 1);
+        assert!(req_map.contains_key(&0));
+
+        // drop the guards of the removed entries is fine
+        drop(waiters);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_drain() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_waiter(0);
+        let waiter_owned = req_map.new_waiter_owned(1);
+
+        let mut drained = req_map.drain();
+        assert!(req_map.is_empty());
+        drained.sort_by_key(|(k, _)| *k);
+        let keys: Vec<_> = drained.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![0, 1]);
+        for (_, waiter) in drained.iter() {
+            waiter.set_rsp(100);
+        }
+
+        // the guards could still be dropped with the entry gone
+        drop(waiter);
+        drop(waiter_owned);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let req_map = WaiterMap::<usize, usize>::with_capacity(1024);
+        assert!(req_map.capacity() >= 1024);
+
+        let waiters: Vec<_> = (0..1024).map(|i| req_map.new_waiter(i)).collect();
+        assert_eq!(req_map.len(), 1024);
+        assert!(req_map.capacity() >= 1024);
+        drop(waiters);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_map_waiter_set_rsp() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiter = req_map.new_waiter(1234);
+        assert_eq!(*waiter.id(), 1234);
+
+        waiter.set_rsp(100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_take_rsp() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_waiter_owned(1234);
+        let err = waiter.wait_rsp(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // the rsp arrives late
+        req_map.set_rsp(&1234, 100).unwrap();
+        assert_eq!(waiter.take_rsp(), Some(100));
+        assert_eq!(waiter.take_rsp(), None);
+    }
+
+    #[test]
+    fn test_try_for_each() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let _waiters: Vec<_> = (0..8).map(|i| req_map.new_waiter(i)).collect();
+
+        let mut keys = Vec::new();
+        let ret = req_map.try_for_each(|k, _waiter| {
+            keys.push(*k);
+            Ok::<_, usize>(())
+        });
+        assert_eq!(ret, Ok(()));
+        keys.sort_unstable();
+        assert_eq!(keys, (0..8).collect::<Vec<_>>());
+
+        // stop at the error on the second entry
+        let mut keys = Vec::new();
+        let ret = req_map.try_for_each(|k, _waiter| {
+            keys.push(*k);
+            if keys.len() == 2 {
+                return Err(*k);
+            }
+            Ok(())
+        });
+        assert_eq!(keys.len(), 2);
+        assert_eq!(ret, Err(keys[1]));
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_waiter_owned(1234);
+
+        let (map, id) = waiter.into_parts();
+        // the entry is still alive without the guard
+        assert!(req_map.contains_key(&1234));
+        map.set_rsp(&id, 100).unwrap();
+
+        let waiter = MapWaiterOwned::from_parts(map, id);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        drop(waiter);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_into_parts_with_clone() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_waiter_owned(1);
+        let waiter_1 = waiter.clone();
+
+        // the rebuilt guard shares the count with the live clone
+        let (map, id) = waiter.into_parts();
+        let waiter = MapWaiterOwned::from_parts(map, id);
+        drop(waiter_1);
+        assert!(req_map.contains_key(&1));
+        drop(waiter);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_deposit_rsp() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+
+        // the rsp arrives before the waiter registered
+        req_map.deposit_rsp(1, 100);
+        let waiter = req_map.get_or_create_waiter(1);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        drop(waiter);
+        assert!(req_map.is_empty());
+
+        // the waiter registered first
+        let waiter = req_map.get_or_create_waiter(2);
+        let req_map_1 = req_map.clone();
+        go!(move || req_map_1.deposit_rsp(2, 200));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 200);
+        drop(waiter);
+
+        // the unconsumed deposit is returned back instead of dropped
+        assert_eq!(req_map.deposit_rsp(3, 300), None);
+        assert_eq!(req_map.deposit_rsp(3, 301), Some(300));
+        req_map.remove_waiter(&3);
+    }
+
+    #[test]
+    fn test_deposit_rsp_new_waiter() {
+        let req_map = Arc::new(WaiterMap::<usize, usize, RandomState, u8>::new());
+
+        // the deposited rsp is taken over by the new waiter
+        req_map.deposit_rsp(1, 100);
+        let waiter = req_map.new_waiter(1);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        // a registered key is not taken over again
+        assert!(req_map.try_new_waiter(1).is_err());
+        drop(waiter);
+        assert!(req_map.is_empty());
+
+        // the meta of the new waiter is kept
+        req_map.deposit_rsp(2, 200);
+        let waiter = req_map.new_waiter_owned_with_meta(2, 7);
+        assert_eq!(req_map.read_meta(&2, |m| *m), Some(7));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 200);
+        drop(waiter);
+
+        // the stored rsp is no longer counted once registered
+        assert_eq!(req_map.set_rsp_or_store(3, 300, 1), Ok(()));
+        assert_eq!(req_map.buffered(), 1);
+        let waiter = req_map.new_waiter(3);
+        assert_eq!(req_map.buffered(), 0);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 300);
+        drop(waiter);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_set_rsp_all() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let waiter = req_map.new_waiter_owned(i);
+                go!(move || waiter.wait_rsp(None))
+            })
+            .collect();
+
+        req_map.set_rsp_all(100);
+        for h in handles {
+            assert_eq!(h.join().unwrap().unwrap(), 100);
+        }
+    }
+
+    #[test]
+    fn test_with_hasher() {
+        use std::hash::{BuildHasherDefault, Hasher};
+
+        // a trivial hasher for the integer keys
+        #[derive(Default)]
+        struct IdHasher(u64);
+
+        impl Hasher for IdHasher {
+            fn finish(&self) -> u64 {
+                self.0
+            }
+
+            fn write(&mut self, bytes: &[u8]) {
+                for b in bytes {
+                    self.0 = (self.0 << 8) | *b as u64;
+                }
+            }
+
+            fn write_usize(&mut self, i: usize) {
+                self.0 = i as u64;
+            }
+        }
+
+        let req_map = Arc::new(WaiterMap::<usize, usize, _>::with_hasher(
+            BuildHasherDefault::<IdHasher>::default(),
+        ));
+        let waiter = req_map.new_waiter_owned(1234);
+        let req_map_1 = req_map.clone();
+        go!(move || req_map_1.set_rsp(&1234, 100).ok());
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_persistent_waiter() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let req_map_1 = req_map.clone();
+
+        let waiter = req_map.new_persistent_waiter(1234);
+        go!(move || req_map_1.set_rsp(&1234, 100).ok());
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        drop(waiter);
+        // the entry is kept after the guard dropped
+        assert!(req_map.contains_key(&1234));
+
+        let waiter = req_map.new_persistent_waiter(1234);
+        req_map.set_rsp(&1234, 200).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 200);
+
+        assert!(req_map.remove_waiter(&1234));
+        assert!(!req_map.remove_waiter(&1234));
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_set_rsp_where() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiters: Vec<_> = (0..6).map(|i| req_map.new_waiter(i)).collect();
+
+        req_map.set_rsp_where(|k| k % 2 == 0, |k| k * 100);
+        for (i, waiter) in waiters.iter().enumerate() {
+            if i % 2 == 0 {
+                assert_eq!(waiter.wait_rsp(None).unwrap(), i * 100);
+            } else {
+                // the other waiters are still pending
+                let err = waiter.wait_rsp(Duration::from_millis(1)).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_while_waiting() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = Arc::new(req_map.new_waiter_owned(1234));
+        let waiter_1 = waiter.clone();
+
+        let h = go!(move || waiter_1.wait_rsp(None));
+        // make sure the coroutine is waiting
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(req_map.remove_waiter(&1234));
+
+        let err = h.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        // the guard could still be dropped safely
+        drop(waiter);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_waiter_meta() {
+        let req_map = WaiterMap::<usize, usize, _, &str>::new();
+        let waiter = req_map.new_waiter_with_meta(1234, "echo");
+        assert_eq!(req_map.read_meta(&1234, |m| m.len()), Some(4));
+
+        req_map.set_rsp(&1234, 100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        // the reply handler could read the metadata on completion
+        assert_eq!(req_map.read_meta(&1234, |m| *m), Some("echo"));
+        drop(waiter);
+        assert_eq!(req_map.read_meta(&1234, |m| *m), None);
+    }
+
+    #[test]
+    fn test_take_ready() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiters: Vec<_> = (0..6).map(|i| req_map.new_waiter(i)).collect();
+        for i in [1, 3, 4] {
+            req_map.set_rsp(&i, i * 100).unwrap();
+        }
+
+        let mut ready = req_map.take_ready();
+        ready.sort_unstable();
+        assert_eq!(ready, vec![(1, 100), (3, 300), (4, 400)]);
+        assert_eq!(req_map.len(), 3);
+        assert!(!req_map.contains_key(&1));
+        assert!(req_map.contains_key(&0));
+        assert!(req_map.take_ready().is_empty());
+        drop(waiters);
+    }
+
+    #[test]
+    fn test_non_clone_key() {
+        // the key type is not `Clone`, an equal key is used for lookup
+        #[derive(Debug, Hash, PartialEq, Eq)]
+        struct ConnKey(Box<str>);
+
+        impl Borrow<str> for ConnKey {
+            fn borrow(&self) -> &str {
+                &self.0
+            }
+        }
+
+        let req_map = Arc::new(WaiterMap::<ConnKey, usize>::new());
+        let waiter = req_map.new_waiter_by_ref(ConnKey("conn-1".into()), "conn-1");
+        assert!(req_map
+            .try_new_waiter_by_ref(ConnKey("conn-1".into()), "conn-1")
+            .is_err());
+
+        let map = req_map.clone();
+        go!(move || {
+            map.set_rsp("conn-1", 42).unwrap();
+        });
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+        assert_eq!(waiter.id(), "conn-1");
+        drop(waiter);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "lookup key doesn't match the id")]
+    fn test_new_waiter_by_ref_mismatch() {
+        let req_map = WaiterMap::<String, usize>::new();
+        let _waiter = req_map.new_waiter_by_ref("conn-1".to_string(), "conn-2");
+    }
+
+    #[test]
+    fn test_wait_rsp_or() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_waiter(1);
+
+        let req_map_1 = req_map.clone();
+        go!(move || req_map_1.set_rsp(&1, 100).ok());
+        assert_eq!(req_map.wait_rsp_or(&1, None, 0), 100);
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(req_map.wait_rsp_or(&1, timeout, 0), 0);
+        assert_eq!(req_map.wait_rsp_or(&2, timeout, 7), 7);
+        drop(waiter);
+    }
+
+    #[test]
+    fn test_map_waiter_weak() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_waiter_weak(1);
+
+        let req_map_1 = req_map.clone();
+        go!(move || req_map_1.set_rsp(&1, 100).ok());
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        assert!(!waiter.is_map_dropped());
+
+        // the guard doesn't keep the map alive
+        drop(req_map);
+        assert!(waiter.is_map_dropped());
+        let err = waiter.wait_rsp(None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(waiter.set_rsp(42), Err(42));
+        assert_eq!(waiter.take_rsp(), None);
+    }
+
+    #[test]
+    fn test_register_and_set() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiter = req_map.register_and_set(1, 100);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        assert!(req_map.contains_key(&1));
+        drop(waiter);
+        assert!(req_map.is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiters: Vec<_> = (0..4).map(|i| req_map.new_waiter_owned(i)).collect();
+        req_map.set_rsp(&0, 100).unwrap();
+        req_map.set_rsp(&1, 101).unwrap();
+
+        let waiter = req_map.new_waiter_owned(4);
+        let h = go!(move || waiter.wait_rsp(None).unwrap());
+        // wait for the coroutine to park
+        while req_map.stats().pending == 0 {
+            may::coroutine::sleep(Duration::from_millis(1));
+        }
+        let stats = req_map.stats();
+        assert_eq!(stats.total, 5);
+        assert_eq!(stats.ready, 2);
+        assert_eq!(stats.pending, 1);
+
+        req_map.set_rsp(&4, 104).unwrap();
+        assert_eq!(h.join().unwrap(), 104);
+        drop(waiters);
+        assert_eq!(req_map.stats(), WaiterStats::default());
+    }
+
+    #[test]
+    fn test_stats_wait_any() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let _waiters: Vec<_> = (0..3).map(|i| req_map.new_waiter(i)).collect();
+
+        // the waiters in a wait_any are counted as pending
+        let req_map_1 = req_map.clone();
+        let h = go!(move || req_map_1.wait_any(&[0, 1], None).unwrap());
+        while req_map.stats().pending == 0 {
+            may::coroutine::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(req_map.stats().pending, 2);
+
+        req_map.set_rsp(&1, 101).unwrap();
+        assert_eq!(h.join().unwrap(), (1, 101));
+        assert_eq!(req_map.stats().pending, 0);
+    }
+
+    #[test]
+    fn test_set_rsp_duplicated() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiter = req_map.new_waiter(1);
+
+        assert_eq!(req_map.set_rsp(&1, 100), Ok(None));
+        // the duplicated reply replaces the unconsumed one
+        assert_eq!(req_map.set_rsp(&1, 101), Ok(Some(100)));
+        assert_eq!(req_map.set_rsp(&2, 200), Err(200));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 101);
+
+        assert!(req_map.try_set_rsp(&1, 102));
+        assert!(!req_map.try_set_rsp(&2, 202));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 102);
+    }
+
+    #[test]
+    fn test_set_rsp_all_ordered() {
+        // each clone records the order it's delivered
+        #[derive(Debug)]
+        struct Order(Arc<AtomicU64>, u64);
+
+        impl Clone for Order {
+            fn clone(&self) -> Self {
+                Order(self.0.clone(), self.0.fetch_add(1, Ordering::Relaxed))
+            }
+        }
+
+        let req_map = WaiterMap::<usize, Order>::new();
+        let keys = [5, 3, 9, 1, 7, 2, 8];
+        let waiters: Vec<_> = keys.iter().map(|k| req_map.new_waiter(*k)).collect();
+
+        req_map.set_rsp_all_ordered(Order(Arc::new(AtomicU64::new(0)), 0));
+        for (i, waiter) in waiters.iter().enumerate() {
+            assert_eq!(waiter.wait_rsp(None).unwrap().1, i as u64);
+        }
+
+        req_map.cancel_all_ordered();
+        for waiter in waiters.iter() {
+            let err = waiter.wait_rsp(None).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        }
+    }
+
+    #[test]
+    fn test_wait_rsp_blocking() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_waiter(1);
+        let req_map_1 = req_map.clone();
+        go!(move || req_map_1.set_rsp(&1, 100).ok());
+        assert_eq!(waiter.wait_rsp_blocking().unwrap(), 100);
+
+        let err = waiter
+            .wait_rsp_timeout(Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_remove_and_take() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiter = req_map.new_persistent_waiter(1);
+        req_map.set_rsp(&1, 100).unwrap();
+
+        assert_eq!(req_map.remove_and_take(&1), Some(100));
+        assert!(!req_map.contains_key(&1));
+        assert_eq!(req_map.remove_and_take(&1), None);
+
+        let _waiter_2 = req_map.new_waiter(2);
+        assert_eq!(req_map.remove_and_take(&2), None);
+        assert!(req_map.is_empty());
+        drop(waiter);
+    }
+
+    #[test]
+    fn test_reset_all() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiters: Vec<_> = (0..3).map(|i| req_map.new_persistent_waiter(i)).collect();
+        for i in 0..3 {
+            req_map.set_rsp(&i, i).unwrap();
+        }
+        assert_eq!(waiters[0].wait_rsp(None).unwrap(), 0);
+
+        // the left rsps are cleared
+        req_map.reset_all();
+        assert_eq!(req_map.stats().ready, 0);
+        let err = waiters[1].wait_rsp(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // the entries are reused for the next round
+        let req_map_1 = req_map.clone();
+        go!(move || req_map_1.set_rsp(&2, 200).ok());
+        assert_eq!(waiters[2].wait_rsp(None).unwrap(), 200);
+        drop(waiters);
+        for i in 0..3 {
+            req_map.remove_waiter(&i);
+        }
+    }
+
+    #[test]
+    fn test_set_rsp_arc() {
+        let req_map = WaiterMap::<usize, Arc<Vec<u8>>>::new();
+        let waiters: Vec<_> = (0..3).map(|i| req_map.new_waiter(i)).collect();
+
+        let payload = Arc::new(vec![0u8; 1024]);
+        assert_eq!(req_map.set_rsp_arc(&[0, 1, 2, 3], payload.clone()), 3);
+        for waiter in waiters.iter() {
+            assert!(Arc::ptr_eq(&waiter.wait_rsp(None).unwrap(), &payload));
+        }
+    }
+
+    #[test]
+    fn test_borrowed_key() {
+        let req_map = Arc::new(WaiterMap::<String, usize>::new());
+        let waiter = req_map.new_waiter("req-1".to_string());
+        assert!(req_map.contains_key("req-1"));
+
+        // look up by &str without allocating a String
+        let req_map_1 = req_map.clone();
+        go!(move || req_map_1.set_rsp("req-1", 100).ok());
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        assert_eq!(req_map.set_rsp("req-2", 200), Err(200));
+        assert!(req_map.cancel_one("req-1"));
+        assert!(req_map.remove_waiter("req-1"));
+        assert!(!req_map.contains_key("req-1"));
+    }
+
+    #[test]
+    fn test_new_waiter_auto() {
+        let req_map = Arc::new(WaiterMap::<u64, usize>::new());
+        // the key taken by hand is skipped
+        let _waiter = req_map.new_waiter_owned(1);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let map = req_map.clone();
+                go!(move || { (0..100).map(|_| map.new_waiter_auto()).collect::<Vec<_>>() })
+            })
+            .collect();
+        let waiters: Vec<_> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+
+        let mut keys: Vec<_> = waiters.iter().map(|(key, _)| *key).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        assert_eq!(keys.len(), 400);
+        assert!(!keys.contains(&1));
+
+        let (key, waiter) = &waiters[0];
+        req_map.set_rsp(key, 100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_clone_owned_waiter() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_waiter_owned(1);
+        let waiter_1 = waiter.clone();
+        let waiter_2 = waiter_1.clone();
+
+        let h = go!(move || waiter_1.set_rsp(100).ok());
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        h.join().unwrap();
+
+        // the entry is kept until the last clone dropped
+        drop(waiter);
+        assert!(req_map.contains_key(&1));
+        let waiter_3 = waiter_2.clone();
+        drop(waiter_2);
+        assert!(req_map.contains_key(&1));
+        drop(waiter_3);
+        assert!(!req_map.contains_key(&1));
+    }
+
+    #[test]
+    fn test_set_rsp_or_store() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiter = req_map.new_waiter(0);
+        // the registered key is not counted
+        assert_eq!(req_map.set_rsp_or_store(0, 100, 2), Ok(()));
+        assert_eq!(req_map.set_rsp_or_store(1, 101, 2), Ok(()));
+        assert_eq!(req_map.set_rsp_or_store(2, 102, 2), Ok(()));
+        assert_eq!(req_map.buffered(), 2);
+        // the buffer is full
+        assert_eq!(req_map.set_rsp_or_store(3, 103, 2), Err(103));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+
+        // register the key to claim the stored rsp
+        let waiter_1 = req_map.get_or_create_waiter(1);
+        assert_eq!(req_map.buffered(), 1);
+        assert_eq!(waiter_1.wait_rsp(None).unwrap(), 101);
+        assert!(req_map.remove_waiter(&2));
+        assert_eq!(req_map.buffered(), 0);
+        assert_eq!(req_map.set_rsp_or_store(3, 103, 2), Ok(()));
+    }
+
+    #[test]
+    fn test_wait_rsp_canceled_panics() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let map = req_map.clone();
+        let h = go!(move || {
+            let waiter = map.new_waiter(1);
+            waiter.wait_rsp(None)
+        });
+        // make sure the coroutine is parked
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound by the cancel panic, which drops the guard
+        assert!(h.join().is_err());
+        assert!(req_map.is_empty());
+    }
 }
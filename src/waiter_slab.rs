@@ -1,60 +1,216 @@
-use sharded_slab::Slab;
+use may::coroutine::{self, ParkError};
+use may::sync::Blocker;
+use scc::HashSet;
+use sharded_slab::{Config, DefaultConfig, Slab};
 
-use crate::waiter::Waiter;
+use crate::waiter::{WaitError, Waiter};
+use crate::WaiterStats;
 
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::sync::Arc;
-use std::time::Duration;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
-pub struct SlabWaiterOwned<T> {
-    slab: Arc<WaiterSlab<T>>,
+// the tag of the next slab, 0 is left for the ids made by `SlabId::from_raw`
+static NEXT_TAG: AtomicUsize = AtomicUsize::new(1);
+const UNTAGGED: usize = 0;
+
+/// the id of a waiter in `WaiterSlab<T>`
+///
+/// it's typed by the rsp type, so that the id of a slab can't be passed to
+/// a slab of another rsp type by accident. it's also tagged with the slab
+/// that made it, the slab rejects the id of another slab of the same type
+/// like a missing waiter
+pub struct SlabId<T> {
+    raw: usize,
+    tag: usize,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> SlabId<T> {
+    fn new(raw: usize, tag: usize) -> Self {
+        SlabId {
+            raw,
+            tag,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// get the raw slab key
+    pub fn as_raw(&self) -> usize {
+        self.raw
+    }
+
+    /// construct the id from the raw slab key returned by `as_raw`
+    ///
+    /// the raw key doesn't carry the slab tag, so the id is accepted by any
+    /// slab of the rsp type. use `WaiterSlab::id_from_raw` to get a tagged one
+    pub fn from_raw(raw: usize) -> Self {
+        SlabId::new(raw, UNTAGGED)
+    }
+}
+
+impl<T> Clone for SlabId<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SlabId<T> {}
+
+impl<T> PartialEq for SlabId<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl<T> Eq for SlabId<T> {}
+
+impl<T> PartialOrd for SlabId<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for SlabId<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.raw.cmp(&other.raw)
+    }
+}
+
+impl<T> Hash for SlabId<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.raw.hash(state)
+    }
+}
+
+impl<T> std::fmt::Debug for SlabId<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SlabId({})", self.raw)
+    }
+}
+
+/// Owned waiter guard to wait the response
+///
+/// the clones share the same entry, it's removed when the last one dropped.
+/// the count of the clones is only allocated by the first clone. only one
+/// clone may wait at a time, the others could set or take the rsp
+pub struct SlabWaiterOwned<T, M = (), C: Config = DefaultConfig> {
+    slab: Arc<WaiterSlab<T, M, C>>,
     entry: usize,
+    // the number of the live clones, shared since the first clone
+    refs: OnceLock<Arc<AtomicUsize>>,
 }
 
-impl<T> SlabWaiterOwned<T> {
+impl<T, M, C: Config> SlabWaiterOwned<T, M, C> {
     /// wait for response
     pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
         self.slab.wait_rsp(self.entry, timeout.into())
     }
 
+    /// wait for response without timeout
+    pub fn wait_rsp_blocking(&self) -> io::Result<T> {
+        self.wait_rsp(None)
+    }
+
+    /// wait for response for at most `timeout`
+    pub fn wait_rsp_timeout(&self, timeout: Duration) -> io::Result<T> {
+        self.wait_rsp(timeout)
+    }
+
+    /// wait for response until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> io::Result<T> {
+        self.slab.wait_rsp_until(self.entry, deadline)
+    }
+
     /// set rsp for the waiter
     pub fn set_rsp(&self, rsp: T) -> Result<(), T> {
-        self.slab.set_rsp(self.entry, rsp)
+        self.slab.set_rsp_raw(self.entry, rsp)
+    }
+
+    /// take the rsp that arrived after the wait returned, e.g. after a timeout
+    pub fn take_rsp(&self) -> Option<T> {
+        self.slab.take_rsp(self.entry)
     }
 
     /// get the id
-    pub fn id(&self) -> usize {
-        self.entry
+    pub fn id(&self) -> SlabId<T> {
+        self.slab.id_from_raw(self.entry)
+    }
+}
+
+impl<T, M, C: Config> Clone for SlabWaiterOwned<T, M, C> {
+    fn clone(&self) -> Self {
+        let refs = self.refs.get_or_init(|| Arc::new(AtomicUsize::new(1)));
+        refs.fetch_add(1, Ordering::Relaxed);
+        SlabWaiterOwned {
+            slab: self.slab.clone(),
+            entry: self.entry,
+            refs: OnceLock::from(refs.clone()),
+        }
     }
 }
 
-impl<T> Drop for SlabWaiterOwned<T> {
+impl<T, M, C: Config> Drop for SlabWaiterOwned<T, M, C> {
     fn drop(&mut self) {
-        // remove the entry
-        self.slab.del_waiter(self.entry);
+        // remove the entry when the last clone dropped, or it's never cloned
+        let last = match self.refs.get() {
+            Some(refs) => refs.fetch_sub(1, Ordering::AcqRel) == 1,
+            None => true,
+        };
+        if last {
+            self.slab.del_waiter(self.entry);
+        }
     }
 }
 
 /// Water guard to wait the response
 #[derive(Debug)]
-pub struct SlabWaiter<'a, T: 'a> {
-    owner: &'a WaiterSlab<T>,
+pub struct SlabWaiter<'a, T: 'a, M: 'a = (), C: Config + 'a = DefaultConfig> {
+    owner: &'a WaiterSlab<T, M, C>,
     entry: usize,
 }
 
-impl<T> SlabWaiter<'_, T> {
+impl<T, M, C: Config> SlabWaiter<'_, T, M, C> {
     /// wait for response
     pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
         self.owner.wait_rsp(self.entry, timeout.into())
     }
 
+    /// wait for response without timeout
+    pub fn wait_rsp_blocking(&self) -> io::Result<T> {
+        self.wait_rsp(None)
+    }
+
+    /// wait for response for at most `timeout`
+    pub fn wait_rsp_timeout(&self, timeout: Duration) -> io::Result<T> {
+        self.wait_rsp(timeout)
+    }
+
+    /// wait for response until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> io::Result<T> {
+        self.owner.wait_rsp_until(self.entry, deadline)
+    }
+
+    /// set rsp for the waiter
+    pub fn set_rsp(&self, rsp: T) -> Result<(), T> {
+        self.owner.set_rsp_raw(self.entry, rsp)
+    }
+
+    /// take the rsp that arrived after the wait returned, e.g. after a timeout
+    pub fn take_rsp(&self) -> Option<T> {
+        self.owner.take_rsp(self.entry)
+    }
+
     /// get the id
-    pub fn id(&self) -> usize {
-        self.entry
+    pub fn id(&self) -> SlabId<T> {
+        self.owner.id_from_raw(self.entry)
     }
 }
 
-impl<T> Drop for SlabWaiter<'_, T> {
+impl<T, M, C: Config> Drop for SlabWaiter<'_, T, M, C> {
     fn drop(&mut self) {
         // remove the entry
         self.owner.del_waiter(self.entry);
@@ -63,54 +219,262 @@ impl<T> Drop for SlabWaiter<'_, T> {
 
 /// Waiter slab that could be used to wait response for given keys
 /// Note: usually you could use Arc<Waiter> directly
-pub struct WaiterSlab<T> {
-    slab: Slab<Waiter<T>>,
+///
+/// the id of each waiter is the generation qualified key of `sharded_slab`,
+/// when a slot is recycled the new waiter gets a different id, so a stale id
+/// would never deliver the rsp to the wrong waiter
+///
+/// `sharded_slab` can't be iterated through a shared ref, so the live ids are
+/// also kept in a concurrent set for `for_each`, `iter`, `stats` and
+/// `cancel_all`. each add and remove of a waiter pays an extra insert and
+/// remove of that set
+pub struct WaiterSlab<T, M = (), C: Config = DefaultConfig> {
+    slab: Slab<Waiter<T, M>, C>,
+    // the live entries, the slab itself can't be iterated through a shared ref
+    keys: HashSet<usize>,
+    // the number of live entries
+    len: AtomicUsize,
+    // the max number of live entries ever reached
+    peak: AtomicUsize,
+    // no new waiter is accepted after shutdown
+    shutdown: AtomicBool,
+    // tags the ids made by this slab
+    tag: usize,
 }
 
-impl<T> std::fmt::Debug for WaiterSlab<T> {
+impl<T, M, C: Config> std::fmt::Debug for WaiterSlab<T, M, C> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "WaiterSlab{{ ... }}")
     }
 }
 
-impl<T> Default for WaiterSlab<T> {
+impl<T, M> Default for WaiterSlab<T, M> {
     fn default() -> Self {
         WaiterSlab::new()
     }
 }
 
-impl<T> WaiterSlab<T> {
+impl<T, M> WaiterSlab<T, M> {
     pub fn new() -> Self {
-        WaiterSlab { slab: Slab::new() }
+        WaiterSlab::with_config()
+    }
+
+    /// create a slab with pages allocated for `cap` waiters
+    ///
+    /// the `sharded_slab` pages are allocated per shard (one shard per thread)
+    /// and never released, so this fills and frees `cap` slots to allocate the
+    /// pages of the current thread's shard, waiters created on other threads
+    /// still grow their own shards
+    pub fn with_capacity(cap: usize) -> Self
+    where
+        M: Default,
+    {
+        let slab = WaiterSlab::new();
+        let entries: Vec<_> = (0..cap)
+            .map(|_| {
+                slab.slab
+                    .insert(Waiter::default())
+                    .expect("no slot available")
+            })
+            .collect();
+        for entry in entries {
+            slab.slab.remove(entry);
+        }
+        slab
+    }
+}
+
+impl<T, M, C: Config> WaiterSlab<T, M, C> {
+    /// create a slab with the `sharded_slab` config, e.g. to tune the page size
+    ///
+    /// `C::MAX_PAGES` and `C::INITIAL_PAGE_SIZE` bound the waiters each thread
+    /// could hold, `try_new_waiter` returns `None` once they are used up
+    pub fn with_config() -> Self {
+        WaiterSlab {
+            slab: Slab::new_with_config::<C>(),
+            keys: HashSet::new(),
+            len: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            tag: NEXT_TAG.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// construct the id of this slab from the raw slab key returned by `SlabId::as_raw`
+    pub fn id_from_raw(&self, raw: usize) -> SlabId<T> {
+        SlabId::new(raw, self.tag)
+    }
+
+    // the raw key of the id, `None` if the id is made by another slab
+    fn raw_key(&self, id: SlabId<T>) -> Option<usize> {
+        (id.tag == UNTAGGED || id.tag == self.tag).then_some(id.raw)
     }
 
     /// return a waiter on the stack!
-    pub fn new_waiter(&self) -> SlabWaiter<T> {
-        let entry = self.slab.insert(Waiter::new()).expect("no slot available");
+    /// panic if the slab is shut down or has no slot available
+    pub fn new_waiter(&self) -> SlabWaiter<'_, T, M, C>
+    where
+        M: Default,
+    {
+        self.new_waiter_with_meta(M::default())
+    }
+
+    /// return a waiter that carries the metadata
+    /// panic if the slab is shut down or has no slot available
+    pub fn new_waiter_with_meta(&self, meta: M) -> SlabWaiter<'_, T, M, C> {
+        let entry = self.add_waiter(meta);
         SlabWaiter { owner: self, entry }
     }
 
-    /// return a waiter on the stack!
-    pub fn new_waiter_owned(self: &Arc<Self>) -> SlabWaiterOwned<T> {
-        let entry = self.slab.insert(Waiter::new()).expect("no slot available");
+    /// return an owned waiter
+    /// panic if the slab is shut down or has no slot available
+    pub fn new_waiter_owned(self: &Arc<Self>) -> SlabWaiterOwned<T, M, C>
+    where
+        M: Default,
+    {
+        self.new_waiter_owned_with_meta(M::default())
+    }
+
+    /// return an owned waiter that carries the metadata
+    /// panic if the slab is shut down or has no slot available
+    pub fn new_waiter_owned_with_meta(self: &Arc<Self>, meta: M) -> SlabWaiterOwned<T, M, C> {
+        let entry = self.add_waiter(meta);
+        self.owned(entry)
+    }
+
+    /// return a waiter that already holds the rsp, the wait would return at once
+    /// panic if the slab is shut down or has no slot available
+    pub fn new_waiter_with_rsp(&self, rsp: T) -> SlabWaiter<'_, T, M, C>
+    where
+        M: Default,
+    {
+        let entry = self.add_waiter_with_rsp(rsp);
+        SlabWaiter { owner: self, entry }
+    }
+
+    /// return an owned waiter that already holds the rsp
+    /// panic if the slab is shut down or has no slot available
+    pub fn new_waiter_owned_with_rsp(self: &Arc<Self>, rsp: T) -> SlabWaiterOwned<T, M, C>
+    where
+        M: Default,
+    {
+        let entry = self.add_waiter_with_rsp(rsp);
+        self.owned(entry)
+    }
+
+    /// return a waiter on the stack, or `None` if the slab is shut down or full
+    pub fn try_new_waiter(&self) -> Option<SlabWaiter<'_, T, M, C>>
+    where
+        M: Default,
+    {
+        let entry = self.try_add_waiter(M::default())?;
+        Some(SlabWaiter { owner: self, entry })
+    }
+
+    /// return an owned waiter, or `None` if the slab is shut down or full
+    pub fn try_new_waiter_owned(self: &Arc<Self>) -> Option<SlabWaiterOwned<T, M, C>>
+    where
+        M: Default,
+    {
+        let entry = self.try_add_waiter(M::default())?;
+        Some(self.owned(entry))
+    }
+
+    // wrap the entry into an owned guard
+    fn owned(self: &Arc<Self>, entry: usize) -> SlabWaiterOwned<T, M, C> {
         SlabWaiterOwned {
             slab: self.clone(),
             entry,
+            refs: OnceLock::new(),
         }
     }
 
+    // used internally
+    fn add_waiter(&self, meta: M) -> usize {
+        self.try_add_waiter(meta)
+            .expect("the slab is shut down or has no slot available")
+    }
+
+    // used internally
+    fn add_waiter_with_rsp(&self, rsp: T) -> usize
+    where
+        M: Default,
+    {
+        let waiter = Waiter::default();
+        waiter.set_rsp(rsp);
+        self.try_insert(waiter)
+            .expect("the slab is shut down or has no slot available")
+    }
+
+    // used internally
+    fn try_add_waiter(&self, meta: M) -> Option<usize> {
+        self.try_insert(Waiter::with_meta(meta))
+    }
+
+    // used internally
+    fn try_insert(&self, waiter: Waiter<T, M>) -> Option<usize> {
+        if self.is_shutdown() {
+            return None;
+        }
+        let entry = self.slab.insert(waiter)?;
+        self.keys.insert(entry).ok();
+        // the shutdown may miss the entry when it's inserted during the scan
+        if self.is_shutdown() {
+            self.keys.remove(&entry);
+            self.slab.remove(entry);
+            return None;
+        }
+        let len = self.len.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(len, Ordering::Relaxed);
+        Some(entry)
+    }
+
     // used internally
     fn del_waiter(&self, id: usize) {
+        if self.keys.remove(&id).is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
         self.slab.remove(id);
     }
 
+    /// the number of waiters in the slab
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// return true if there is no waiter in the slab
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the max number of waiters the slab ever held at the same time
+    pub fn peak_len(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
     fn wait_rsp(&self, id: usize, timeout: Option<Duration>) -> io::Result<T> {
         let waiter = self.slab.get(id).expect("can't find id in waiter slab");
         waiter.wait_rsp(timeout)
     }
 
+    fn wait_rsp_until(&self, id: usize, deadline: Instant) -> io::Result<T> {
+        let waiter = self.slab.get(id).expect("can't find id in waiter slab");
+        waiter.wait_rsp_until(deadline)
+    }
+
+    fn take_rsp(&self, id: usize) -> Option<T> {
+        self.slab.get(id).and_then(|waiter| waiter.take_rsp())
+    }
+
     /// set rsp for the corresponding waiter
-    pub fn set_rsp(&self, id: usize, rsp: T) -> Result<(), T> {
+    pub fn set_rsp(&self, id: SlabId<T>, rsp: T) -> Result<(), T> {
+        match self.raw_key(id) {
+            Some(raw) => self.set_rsp_raw(raw, rsp),
+            None => Err(rsp),
+        }
+    }
+
+    fn set_rsp_raw(&self, id: usize, rsp: T) -> Result<(), T> {
         match self.slab.get(id) {
             Some(waiter) => {
                 waiter.set_rsp(rsp);
@@ -119,6 +483,157 @@ impl<T> WaiterSlab<T> {
             None => Err(rsp),
         }
     }
+
+    /// wait for all the waiters of the ids, the rsps are in the same order of the ids
+    ///
+    /// if timeout, the error reports the ids that still pending, and the
+    /// received rsps are kept so that we could wait again. a canceled
+    /// waiter fails the wait with `Interrupted` error
+    pub fn wait_all(&self, ids: &[SlabId<T>], timeout: Option<Duration>) -> io::Result<Vec<T>> {
+        let mut waiters = Vec::with_capacity(ids.len());
+        for id in ids {
+            match self.raw_key(*id).and_then(|raw| self.slab.get(raw)) {
+                Some(waiter) => waiters.push(waiter),
+                None => {
+                    let msg = format!("can't find {id:?} in waiter slab");
+                    return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+                }
+            }
+        }
+
+        // register one blocker on all the waiters, so that any rsp would wake us up
+        // the coroutine cancel is returned by the park so that we could unregister
+        let blocker = Arc::new(Blocker::new(true));
+        for waiter in waiters.iter() {
+            waiter.add_observer(&blocker);
+        }
+        let deadline = timeout.map(|d| Instant::now() + d);
+        let mut cancel_panic = false;
+        let ret = loop {
+            if waiters.iter().all(|w| w.is_ready()) {
+                break Ok(());
+            }
+            // a canceled waiter would never get the rsp
+            if waiters.iter().any(|w| w.is_canceled() && !w.is_ready()) {
+                break Err(WaitError::Canceled);
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        break Err(WaitError::Timeout);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+
+            match blocker.park(timeout) {
+                Ok(_) => {}
+                Err(ParkError::Timeout) => break Err(WaitError::Timeout),
+                Err(ParkError::Canceled) => {
+                    cancel_panic = true;
+                    break Err(WaitError::Canceled);
+                }
+            }
+        };
+
+        for waiter in waiters.iter() {
+            waiter.remove_observer(&blocker);
+        }
+        // the coroutine cancel unwinds like `Waiter::wait_rsp`
+        if cancel_panic {
+            coroutine::trigger_cancel_panic();
+        }
+
+        match ret {
+            Ok(()) => Ok(waiters
+                .iter()
+                .map(|w| w.try_wait_rsp().expect("waiter rsp is ready"))
+                .collect()),
+            Err(WaitError::Timeout) => {
+                let pending: Vec<_> = ids
+                    .iter()
+                    .zip(waiters.iter())
+                    .filter(|(_, w)| !w.is_ready())
+                    .map(|(id, _)| id.raw)
+                    .collect();
+                let msg = format!("wait all timeout, pending ids: {pending:?}");
+                Err(io::Error::new(io::ErrorKind::TimedOut, msg))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// call `f` with the metadata of the waiter, return `None` if there is no such waiter
+    pub fn read_meta<R, F: FnOnce(&M) -> R>(&self, id: SlabId<T>, f: F) -> Option<R> {
+        let waiter = self.slab.get(self.raw_key(id)?)?;
+        Some(f(waiter.meta()))
+    }
+
+    /// set rsp for the corresponding waiter, return false if there is no such waiter
+    /// the rsp is dropped if not delivered
+    pub fn try_set_rsp(&self, id: SlabId<T>, rsp: T) -> bool {
+        self.set_rsp(id, rsp).is_ok()
+    }
+
+    /// call the closure for each waiter in the slab
+    pub fn for_each<F: FnMut(SlabId<T>, &Waiter<T, M>)>(&self, mut f: F) {
+        // collect the keys first, so that the closure could touch the slab freely
+        let mut keys = Vec::new();
+        self.keys.scan(|k| keys.push(*k));
+        for id in keys {
+            if let Some(waiter) = self.slab.get(id) {
+                f(self.id_from_raw(id), &waiter);
+            }
+        }
+    }
+
+    /// count the waiters in the slab
+    pub fn stats(&self) -> WaiterStats {
+        let mut stats = WaiterStats::default();
+        self.keys.scan(|id| {
+            if let Some(waiter) = self.slab.get(*id) {
+                stats.add(&waiter);
+            }
+        });
+        stats
+    }
+
+    /// iterate the live waiters, yield the id and whether the rsp is ready
+    ///
+    /// `sharded_slab::unique_iter` needs `&mut self`, so this iterates a snapshot
+    /// of the ids, the waiters removed after that are skipped and the new
+    /// added ones are not visited
+    pub fn iter(&self) -> impl Iterator<Item = (SlabId<T>, bool)> + '_ {
+        let mut keys = Vec::new();
+        self.keys.scan(|k| keys.push(*k));
+        keys.into_iter().filter_map(move |id| {
+            let waiter = self.slab.get(id)?;
+            Some((self.id_from_raw(id), waiter.is_ready()))
+        })
+    }
+
+    /// cancel all the waiting waiter, all wait would return Interrupted error
+    pub fn cancel_all(&self) {
+        self.for_each(|_id, waiter| waiter.cancel_wait());
+    }
+
+    /// cancel all the waiters and reject the new ones
+    ///
+    /// the guards borrow or own the slab, so it can't be dropped while they
+    /// are waiting, call this to wake them up for a clean shutdown. after that
+    /// the `try_new_*` constructors return `None` and the others panic
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.cancel_all();
+    }
+
+    /// check if the slab is shut down
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
 }
 
 #[cfg(test)]
@@ -162,4 +677,351 @@ mod tests {
         let result = waiter.wait_rsp(None).unwrap();
         assert_eq!(result, 100);
     }
+
+    #[test]
+    fn test_slab_waiter_until() {
+        let req_slab = Arc::new(WaiterSlab::<usize>::new());
+        let req_slab_1 = req_slab.clone();
+
+        let waiter = req_slab.new_waiter();
+        let id = waiter.id();
+        // the deadline is already passed
+        let err = waiter.wait_rsp_until(Instant::now()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        go!(move || req_slab_1.set_rsp(id, 100).ok());
+        let deadline = Instant::now() + Duration::from_secs(2);
+        assert_eq!(waiter.wait_rsp_until(deadline).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_cancel_all() {
+        let req_slab = Arc::new(WaiterSlab::<usize>::new());
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let waiter = req_slab.new_waiter_owned();
+                go!(move || waiter.wait_rsp(None))
+            })
+            .collect();
+
+        let mut count = 0;
+        req_slab.for_each(|_id, _waiter| count += 1);
+        assert_eq!(count, 4);
+
+        req_slab.cancel_all();
+        for h in handles {
+            let err = h.join().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        }
+    }
+
+    #[test]
+    fn test_len() {
+        let req_slab = WaiterSlab::<usize>::new();
+        assert!(req_slab.is_empty());
+
+        let mut waiters: Vec<_> = (0..4).map(|_| req_slab.new_waiter()).collect();
+        assert_eq!(req_slab.len(), 4);
+
+        waiters.truncate(1);
+        assert_eq!(req_slab.len(), 1);
+        assert!(!req_slab.is_empty());
+        assert_eq!(req_slab.peak_len(), 4);
+
+        drop(waiters);
+        assert!(req_slab.is_empty());
+        assert_eq!(req_slab.peak_len(), 4);
+    }
+
+    #[test]
+    fn test_stale_id() {
+        let req_slab = WaiterSlab::<usize>::new();
+
+        let waiter = req_slab.new_waiter();
+        let stale_id = waiter.id();
+        drop(waiter);
+
+        // the slot is recycled with a new generation
+        let waiter = req_slab.new_waiter();
+        assert_ne!(waiter.id(), stale_id);
+        assert_eq!(req_slab.set_rsp(stale_id, 100), Err(100));
+
+        req_slab.set_rsp(waiter.id(), 200).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 200);
+    }
+
+    #[test]
+    fn test_try_new_waiter() {
+        let req_slab = Arc::new(WaiterSlab::<usize>::new());
+        let waiter = req_slab.try_new_waiter().unwrap();
+        let waiter_owned = req_slab.try_new_waiter_owned().unwrap();
+        assert_ne!(waiter.id(), waiter_owned.id());
+        assert_eq!(req_slab.len(), 2);
+
+        waiter_owned.set_rsp(100).unwrap();
+        assert_eq!(waiter_owned.wait_rsp(None).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let req_slab = WaiterSlab::<usize>::with_capacity(64);
+        assert!(req_slab.is_empty());
+        assert_eq!(req_slab.peak_len(), 0);
+
+        let waiters: Vec<_> = (0..64).map(|_| req_slab.new_waiter()).collect();
+        assert_eq!(req_slab.len(), 64);
+        assert_eq!(req_slab.peak_len(), 64);
+        for (i, waiter) in waiters.iter().enumerate() {
+            req_slab.set_rsp(waiter.id(), i).unwrap();
+            assert_eq!(waiter.wait_rsp(None).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_slab_waiter_set_rsp() {
+        let req_slab = WaiterSlab::<usize>::new();
+        let waiter = req_slab.new_waiter();
+
+        waiter.set_rsp(100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_take_rsp() {
+        let req_slab = WaiterSlab::<usize>::new();
+        let waiter = req_slab.new_waiter();
+        let err = waiter.wait_rsp(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // the rsp arrives late
+        waiter.set_rsp(100).unwrap();
+        assert_eq!(waiter.take_rsp(), Some(100));
+        assert_eq!(waiter.take_rsp(), None);
+    }
+
+    #[test]
+    fn test_try_set_rsp() {
+        let req_slab = WaiterSlab::<usize>::new();
+        let waiter = req_slab.new_waiter();
+        let id = waiter.id();
+
+        assert!(req_slab.try_set_rsp(id, 100));
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+
+        drop(waiter);
+        assert!(!req_slab.try_set_rsp(id, 200));
+    }
+
+    #[test]
+    fn test_iter() {
+        let req_slab = WaiterSlab::<usize>::new();
+        let waiters: Vec<_> = (0..4).map(|_| req_slab.new_waiter()).collect();
+        waiters[0].set_rsp(100).unwrap();
+
+        let mut entries: Vec<_> = req_slab.iter().collect();
+        entries.sort_unstable();
+        let mut expected: Vec<_> = waiters.iter().map(|w| (w.id(), false)).collect();
+        expected[0].1 = true;
+        expected.sort_unstable();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_slab_id() {
+        let req_slab = WaiterSlab::<usize>::new();
+        let waiter = req_slab.new_waiter();
+        let id = waiter.id();
+
+        // the raw key could be sent out and converted back
+        let raw = id.as_raw();
+        let id_1 = SlabId::<usize>::from_raw(raw);
+        assert_eq!(id, id_1);
+        req_slab.set_rsp(id_1, 100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        assert_eq!(req_slab.id_from_raw(raw), id);
+    }
+
+    #[test]
+    fn test_slab_id_of_other_slab() {
+        let req_slab = WaiterSlab::<usize>::new();
+        let other_slab = WaiterSlab::<usize>::new();
+        let waiter = req_slab.new_waiter();
+        let other = other_slab.new_waiter();
+        // the same raw key in both slabs
+        assert_eq!(waiter.id().as_raw(), other.id().as_raw());
+
+        // the id of the other slab is rejected like a missing waiter
+        assert_eq!(req_slab.set_rsp(other.id(), 100), Err(100));
+        assert_eq!(req_slab.read_meta(other.id(), |_| ()), None);
+        let err = req_slab.wait_all(&[other.id()], None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(waiter.take_rsp(), None);
+
+        other_slab.set_rsp(other.id(), 200).unwrap();
+        assert_eq!(other.wait_rsp(None).unwrap(), 200);
+    }
+
+    #[test]
+    fn test_wait_all_canceled_panics() {
+        let req_slab = Arc::new(WaiterSlab::<usize>::new());
+        let waiters: Vec<_> = (0..2).map(|_| req_slab.new_waiter()).collect();
+        let ids: Vec<_> = waiters.iter().map(|w| w.id()).collect();
+
+        let req_slab_1 = req_slab.clone();
+        let h = go!(move || req_slab_1.wait_all(&ids, None));
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { h.coroutine().cancel() };
+        // the coroutine is unwound after the blocker is unregistered
+        assert!(h.join().is_err());
+        req_slab.for_each(|_id, waiter| assert_eq!(waiter.observers(), 0));
+    }
+
+    #[test]
+    fn test_wait_all() {
+        let req_slab = Arc::new(WaiterSlab::<usize>::new());
+        let waiters: Vec<_> = (0..3).map(|_| req_slab.new_waiter()).collect();
+        let ids: Vec<_> = waiters.iter().map(|w| w.id()).collect();
+
+        // only part of the rsps arrived
+        req_slab.set_rsp(ids[2], 200).unwrap();
+        req_slab.set_rsp(ids[0], 0).unwrap();
+        let err = req_slab
+            .wait_all(&ids, Some(Duration::from_millis(10)))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains(&ids[1].as_raw().to_string()));
+
+        let req_slab_1 = req_slab.clone();
+        let id = ids[1];
+        go!(move || req_slab_1.set_rsp(id, 100).ok());
+        let rsps = req_slab.wait_all(&ids, None).unwrap();
+        assert_eq!(rsps, vec![0, 100, 200]);
+    }
+
+    #[test]
+    fn test_waiter_meta() {
+        let req_slab = Arc::new(WaiterSlab::<usize, String>::new());
+        let waiter = req_slab.new_waiter_owned_with_meta("echo".to_string());
+        let id = waiter.id();
+
+        waiter.set_rsp(100).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        // the reply handler could read the metadata on completion
+        assert_eq!(req_slab.read_meta(id, |m| m.clone()).unwrap(), "echo");
+        drop(waiter);
+        assert_eq!(req_slab.read_meta(id, |m| m.clone()), None);
+    }
+
+    #[test]
+    fn test_new_waiter_with_rsp() {
+        let slab = Arc::new(WaiterSlab::<usize>::new());
+        let waiter = slab.new_waiter_with_rsp(42);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+        assert_eq!(slab.len(), 1);
+        drop(waiter);
+
+        let waiter = slab.new_waiter_owned_with_rsp(43);
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 43);
+        drop(waiter);
+        assert!(slab.is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let slab = Arc::new(WaiterSlab::<usize>::new());
+        let waiters: Vec<_> = (0..3).map(|_| slab.new_waiter()).collect();
+        waiters[0].set_rsp(100).unwrap();
+
+        let waiter = slab.new_waiter_owned();
+        let id = waiter.id();
+        let h = go!(move || waiter.wait_rsp(None).unwrap());
+        // wait for the coroutine to park
+        while slab.stats().pending == 0 {
+            may::coroutine::sleep(Duration::from_millis(1));
+        }
+        let stats = slab.stats();
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.ready, 1);
+        assert_eq!(stats.pending, 1);
+
+        slab.set_rsp(id, 101).unwrap();
+        assert_eq!(h.join().unwrap(), 101);
+        drop(waiters);
+        assert_eq!(slab.stats(), WaiterStats::default());
+    }
+
+    struct SmallPages;
+
+    impl Config for SmallPages {
+        const INITIAL_PAGE_SIZE: usize = 4;
+        const MAX_PAGES: usize = 1;
+    }
+
+    #[test]
+    fn test_with_config() {
+        let slab = Arc::new(WaiterSlab::<usize, (), SmallPages>::with_config());
+        let waiter = slab.new_waiter_owned();
+        let id = waiter.id();
+        let slab_1 = slab.clone();
+        go!(move || slab_1.set_rsp(id, 42).ok());
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+        drop(waiter);
+        assert!(slab.is_empty());
+
+        // only one page of 4 slots for this thread
+        let waiters: Vec<_> = (0..4).map(|_| slab.try_new_waiter().unwrap()).collect();
+        assert!(slab.try_new_waiter().is_none());
+        assert!(slab.try_new_waiter_owned().is_none());
+        // the slots are reused after removed
+        drop(waiters);
+        assert!(slab.try_new_waiter().is_some());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let slab = Arc::new(WaiterSlab::<usize>::new());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let waiter = slab.new_waiter_owned();
+                go!(move || waiter.wait_rsp(None))
+            })
+            .collect();
+
+        // the cancel is kept by the waiter, so it's fine if a wait starts late
+        slab.shutdown();
+        for h in handles {
+            let err = h.join().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        }
+        assert!(slab.is_shutdown());
+        assert!(slab.try_new_waiter().is_none());
+        assert!(slab.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "the slab is shut down")]
+    fn test_new_waiter_after_shutdown() {
+        let slab = WaiterSlab::<usize>::new();
+        slab.shutdown();
+        let _waiter = slab.new_waiter_with_rsp(100);
+    }
+
+    #[test]
+    fn test_clone_owned_waiter() {
+        let slab = Arc::new(WaiterSlab::<usize>::new());
+        let waiter = slab.new_waiter_owned();
+        let waiter_1 = waiter.clone();
+
+        let h = go!(move || waiter_1.set_rsp(100).ok());
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 100);
+        h.join().unwrap();
+
+        // the entry is kept until the last clone dropped
+        let waiter_2 = waiter.clone();
+        drop(waiter);
+        assert_eq!(slab.len(), 1);
+        drop(waiter_2);
+        assert!(slab.is_empty());
+    }
 }